@@ -0,0 +1,4 @@
+-- SHA-256 of the original file bytes, used to deduplicate re-ingested media
+ALTER TABLE media ADD COLUMN content_hash TEXT;
+
+CREATE UNIQUE INDEX media_content_hash_idx ON media (content_hash);
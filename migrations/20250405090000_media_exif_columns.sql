@@ -0,0 +1,9 @@
+-- Capture time and location extracted from EXIF, kept out of the metadata
+-- JSONB so they can be indexed and filtered on
+ALTER TABLE media
+    ADD COLUMN captured_at TIMESTAMP,
+    ADD COLUMN latitude DOUBLE PRECISION,
+    ADD COLUMN longitude DOUBLE PRECISION;
+
+CREATE INDEX media_captured_at_idx ON media (captured_at);
+CREATE INDEX media_location_idx ON media (latitude, longitude);
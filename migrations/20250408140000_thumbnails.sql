@@ -0,0 +1,10 @@
+-- Downscaled copies of each media item, stored under storage.media_path
+CREATE TABLE thumbnails (
+    media_id UUID REFERENCES media(id) ON DELETE CASCADE,
+    size INTEGER NOT NULL, -- Longest edge bound in pixels
+    file_path TEXT NOT NULL,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (media_id, size)
+);
@@ -0,0 +1,4 @@
+-- Set when a media item's file disappears from disk, cleared when it returns
+ALTER TABLE media ADD COLUMN missing_at TIMESTAMPTZ;
+
+CREATE INDEX media_missing_at_idx ON media (missing_at) WHERE missing_at IS NOT NULL;
@@ -0,0 +1,3 @@
+-- Modification time of the file when it was last ingested or verified, so a
+-- rescan can skip hashing files whose size and mtime are unchanged
+ALTER TABLE media ADD COLUMN file_mtime TIMESTAMPTZ;
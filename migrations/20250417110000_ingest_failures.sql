@@ -0,0 +1,9 @@
+-- Files that failed to ingest, kept until a later ingest of the same path
+-- succeeds so they can be retried
+CREATE TABLE ingest_failures (
+    file_path TEXT PRIMARY KEY,
+    stage TEXT NOT NULL, -- decode, embed, import or db
+    error TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 1,
+    failed_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,22 @@
+-- Ingestion runs, persisted so an interrupted run can be resumed
+CREATE TABLE ingest_jobs (
+    id UUID PRIMARY KEY,
+    status TEXT NOT NULL DEFAULT 'pending', -- pending, running, completed or cancelled
+    source TEXT NOT NULL, -- What the job was created from, for display
+    config JSONB NOT NULL, -- Ingest settings the job runs with
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    finished_at TIMESTAMPTZ
+);
+
+-- The files of a job and what happened to each
+CREATE TABLE ingest_job_files (
+    job_id UUID REFERENCES ingest_jobs(id) ON DELETE CASCADE,
+    file_path TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending', -- pending, new, moved, skipped or failed
+    media_id UUID,
+    error TEXT,
+    PRIMARY KEY (job_id, file_path)
+);
+
+CREATE INDEX ingest_job_files_status_idx ON ingest_job_files (job_id, status);
@@ -0,0 +1,4 @@
+-- The process currently running a job. It refreshes updated_at while it
+-- works, so a running job whose updated_at is stale was left by a process
+-- that died and may be taken over.
+ALTER TABLE ingest_jobs ADD COLUMN runner_id UUID;
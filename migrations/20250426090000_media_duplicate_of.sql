@@ -0,0 +1,4 @@
+-- Media stored before deduplication whose content turned out to be a copy
+-- of another item. They keep a NULL content_hash, since the hash is unique,
+-- and point at the item holding it instead.
+ALTER TABLE media ADD COLUMN duplicate_of UUID REFERENCES media (id) ON DELETE SET NULL;
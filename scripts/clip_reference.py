@@ -0,0 +1,70 @@
+"""Write the reference CLIP embeddings used by the preprocessing regression test.
+
+The embeddings come from Hugging Face transformers, which is the reference
+implementation of the model's preprocessing. Regenerate them whenever the
+fixture image, texts or model revision change:
+
+    pip install torch transformers pillow numpy
+    python scripts/clip_reference.py
+    cargo test test_clip_matches_reference_embeddings -- --ignored
+"""
+
+import json
+import pathlib
+
+import numpy as np
+import torch
+from PIL import Image
+from transformers import CLIPModel, CLIPProcessor
+
+MODEL_ID = "openai/clip-vit-base-patch32"
+REVISION = "refs/pr/15"
+TEXTS = ["a colourful gradient", "a photo of a dog playing in the snow"]
+OUTPUT = (
+    pathlib.Path(__file__).resolve().parent.parent
+    / "tests"
+    / "fixtures"
+    / "clip-vit-base-patch32.json"
+)
+
+
+def fixture_image():
+    """A 64x48 gradient, matching `fixture_image` in src/core/embedding.rs."""
+    x = np.arange(64)[None, :]
+    y = np.arange(48)[:, None]
+    channels = np.broadcast_arrays(x * 4, y * 5, (x + y) * 2)
+    return Image.fromarray(np.stack(channels, axis=-1).astype(np.uint8), "RGB")
+
+
+def main():
+    model = CLIPModel.from_pretrained(MODEL_ID, revision=REVISION).eval()
+    processor = CLIPProcessor.from_pretrained(MODEL_ID, revision=REVISION)
+
+    inputs = processor(
+        text=TEXTS, images=fixture_image(), return_tensors="pt", padding=True
+    )
+    with torch.no_grad():
+        image = model.get_image_features(pixel_values=inputs["pixel_values"])
+        texts = model.get_text_features(
+            input_ids=inputs["input_ids"], attention_mask=inputs["attention_mask"]
+        )
+
+    image = torch.nn.functional.normalize(image, dim=-1)[0]
+    texts = torch.nn.functional.normalize(texts, dim=-1)
+
+    OUTPUT.parent.mkdir(parents=True, exist_ok=True)
+    with OUTPUT.open("w") as f:
+        json.dump(
+            {
+                "model_id": MODEL_ID,
+                "revision": REVISION,
+                "image": image.tolist(),
+                "texts": {text: row.tolist() for text, row in zip(TEXTS, texts)},
+            },
+            f,
+        )
+    print(f"Wrote {OUTPUT}")
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,91 @@
+use crate::core::jobs::JobError;
+use crate::core::tags::TagError;
+use actix_web::error::BlockingError;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde_json::json;
+use thiserror::Error;
+use tracing::error;
+
+/// Errors returned by the API handlers.
+///
+/// Every variant is rendered as a JSON body of the form `{"error": "..."}`
+/// with the matching HTTP status code.
+#[derive(Debug, Error)]
+pub enum ApiError {
+    #[error("{0}")]
+    BadRequest(String),
+
+    #[error("{0}")]
+    NotFound(String),
+
+    #[error("{0}")]
+    Conflict(String),
+
+    #[error("Internal server error")]
+    Internal(#[from] anyhow::Error),
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(err: sqlx::Error) -> Self {
+        ApiError::Internal(err.into())
+    }
+}
+
+impl From<std::io::Error> for ApiError {
+    fn from(err: std::io::Error) -> Self {
+        ApiError::Internal(err.into())
+    }
+}
+
+impl From<BlockingError> for ApiError {
+    fn from(err: BlockingError) -> Self {
+        ApiError::Internal(err.into())
+    }
+}
+
+impl From<TagError> for ApiError {
+    fn from(err: TagError) -> Self {
+        match err {
+            TagError::MediaNotFound(_) | TagError::TagNotFound(_) => {
+                ApiError::NotFound(err.to_string())
+            }
+            TagError::AlreadyExists(_) => ApiError::Conflict(err.to_string()),
+            TagError::EmptyName => ApiError::BadRequest(err.to_string()),
+            TagError::Database(e) => e.into(),
+        }
+    }
+}
+
+impl From<JobError> for ApiError {
+    fn from(err: JobError) -> Self {
+        match err {
+            JobError::NotFound(_) => ApiError::NotFound(err.to_string()),
+            JobError::Finished(..) | JobError::Busy(_) => ApiError::Conflict(err.to_string()),
+            JobError::Database(e) => e.into(),
+            JobError::Other(e) => ApiError::Internal(e),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        // Internal errors are logged in full but only reported generically
+        if let ApiError::Internal(err) = self {
+            error!("Internal server error: {:#}", err);
+        }
+
+        HttpResponse::build(self.status_code()).json(json!({ "error": self.to_string() }))
+    }
+}
+
+pub type ApiResult<T> = Result<T, ApiError>;
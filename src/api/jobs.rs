@@ -0,0 +1,55 @@
+use super::error::ApiResult;
+use crate::core::jobs;
+use crate::core::state::AppState;
+use actix_web::{get, post, web, HttpResponse};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+#[get("/jobs")]
+pub async fn list_jobs(state: web::Data<AppState>) -> ApiResult<HttpResponse> {
+    let jobs = jobs::list_jobs(&state.db_pool).await?;
+    Ok(HttpResponse::Ok().json(jobs))
+}
+
+#[get("/jobs/{id}")]
+pub async fn get_job(state: web::Data<AppState>, path: web::Path<Uuid>) -> ApiResult<HttpResponse> {
+    let job = jobs::get_job(&state.db_pool, path.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(job))
+}
+
+/// Resume a job in the background. Poll `GET /jobs/{id}` for progress.
+///
+/// Responds with 409 if the job has finished or another process is running
+/// it.
+#[post("/jobs/{id}/resume")]
+pub async fn resume_job(
+    state: web::Data<AppState>,
+    path: web::Path<Uuid>,
+) -> ApiResult<HttpResponse> {
+    let job_id = path.into_inner();
+    // Claim before responding so a job already running elsewhere is refused
+    let lease = jobs::claim_job(&state.db_pool, job_id).await?;
+    let job = jobs::get_job(&state.db_pool, job_id).await?;
+
+    let state = state.get_ref().clone();
+    actix_web::rt::spawn(async move {
+        match jobs::run_claimed_job(lease, &state, |_| {}).await {
+            Ok(summary) => info!(
+                "Job {} finished: {} new, {} moved, {} skipped, {} failed",
+                job_id, summary.new, summary.moved, summary.skipped, summary.failed
+            ),
+            Err(e) => warn!("Job {} failed: {:#}", job_id, e),
+        }
+    });
+
+    Ok(HttpResponse::Accepted().json(job))
+}
+
+#[post("/jobs/{id}/cancel")]
+pub async fn cancel_job(
+    state: web::Data<AppState>,
+    path: web::Path<Uuid>,
+) -> ApiResult<HttpResponse> {
+    let job = jobs::cancel_job(&state.db_pool, path.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(job))
+}
@@ -0,0 +1,247 @@
+use super::error::{ApiError, ApiResult};
+use crate::core::config::ImportMode;
+use crate::core::ingest::{process_image, IngestOutcome};
+use crate::core::library::is_library_file;
+use crate::core::media::extract_media_details_from_path;
+use crate::core::state::AppState;
+use crate::core::thumbnails::{list_thumbnails, select_thumbnail};
+use actix_multipart::Multipart;
+use actix_web::http::header::{CacheControl, CacheDirective};
+use actix_web::{delete, get, post, web, HttpResponse};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tracing::warn;
+use uuid::Uuid;
+
+#[derive(Debug, Serialize)]
+pub struct MediaResponse {
+    pub id: Uuid,
+    pub filename: String,
+    pub content_type: String,
+    pub file_path: String,
+    pub file_size: i64,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub metadata: Option<serde_json::Value>,
+    pub captured_at: Option<NaiveDateTime>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// When the file was found to be missing from disk, if it is
+    pub missing_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ThumbnailParams {
+    /// Minimum longest edge in pixels; the closest stored size is served
+    pub size: Option<u32>,
+}
+
+/// Fetch a single media row, returning `NotFound` if it doesn't exist.
+pub async fn fetch_media(state: &AppState, media_id: Uuid) -> ApiResult<MediaResponse> {
+    sqlx::query_as!(
+        MediaResponse,
+        r#"
+        SELECT id, filename, content_type, file_path, file_size, width, height, created_at, metadata,
+               captured_at, latitude, longitude, missing_at
+        FROM media
+        WHERE id = $1
+        "#,
+        media_id
+    )
+    .fetch_optional(&state.db_pool)
+    .await?
+    .ok_or_else(|| ApiError::NotFound(format!("Media not found: {}", media_id)))
+}
+
+/// Upload one or more images as multipart form data.
+///
+/// Each file field is written to the configured media directory, embedded
+/// and stored. Non-file fields are ignored.
+#[post("/media")]
+pub async fn upload_media(
+    state: web::Data<AppState>,
+    mut payload: Multipart,
+) -> ApiResult<HttpResponse> {
+    let mut uploaded = Vec::new();
+
+    while let Some(field) = payload.next().await {
+        let mut field = field.map_err(|e| ApiError::BadRequest(e.to_string()))?;
+
+        let filename = match field
+            .content_disposition()
+            .and_then(|cd| cd.get_filename())
+            .and_then(sanitize_filename)
+        {
+            Some(filename) => filename,
+            None => continue,
+        };
+
+        let stored_path =
+            state
+                .config
+                .storage
+                .media_path
+                .join(format!("{}-{}", Uuid::new_v4(), filename));
+
+        let mut file = tokio::fs::File::create(&stored_path).await?;
+        while let Some(chunk) = field.next().await {
+            let chunk = chunk.map_err(|e| ApiError::BadRequest(e.to_string()))?;
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+
+        let outcome = match ingest_upload(&state, &stored_path, &filename).await {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                // Don't leave orphaned files behind for uploads we rejected
+                let _ = tokio::fs::remove_file(&stored_path).await;
+                return Err(e);
+            }
+        };
+
+        if let IngestOutcome::Skipped(_) = outcome {
+            // Duplicate of existing media, so the stored copy isn't needed
+            let _ = tokio::fs::remove_file(&stored_path).await;
+        }
+
+        uploaded.push(fetch_media(&state, outcome.media_id()).await?);
+    }
+
+    if uploaded.is_empty() {
+        return Err(ApiError::BadRequest(
+            "No files found in multipart upload".to_string(),
+        ));
+    }
+
+    Ok(HttpResponse::Created().json(uploaded))
+}
+
+async fn ingest_upload(
+    state: &AppState,
+    stored_path: &PathBuf,
+    filename: &str,
+) -> ApiResult<IngestOutcome> {
+    // Decoding a large image would stall every request on this worker
+    let path = stored_path.clone();
+    let mut media_details =
+        web::block(move || extract_media_details_from_path(&path).map_err(|e| e.to_string()))
+            .await?
+            .map_err(|e| {
+                ApiError::BadRequest(format!("Could not read image {}: {}", filename, e))
+            })?;
+
+    // Keep the name the client uploaded rather than the prefixed one on disk
+    media_details.filename = filename.to_string();
+
+    // Uploads are already inside media_path, so in a managed library they are
+    // moved into place rather than copied next to the upload
+    let import_mode = match state.config.ingest.import_mode {
+        ImportMode::Reference => ImportMode::Reference,
+        ImportMode::Copy | ImportMode::Move => ImportMode::Move,
+    };
+
+    Ok(process_image(media_details, import_mode, state).await?)
+}
+
+/// Strip any directory components from a client-supplied filename.
+fn sanitize_filename(filename: &str) -> Option<String> {
+    Path::new(filename)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .filter(|name| !name.is_empty())
+}
+
+#[get("/media/{id}")]
+pub async fn get_media(
+    state: web::Data<AppState>,
+    path: web::Path<Uuid>,
+) -> ApiResult<HttpResponse> {
+    let media = fetch_media(&state, path.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(media))
+}
+
+/// Delete a media item along with the files the library owns for it: its
+/// thumbnails and, if it was uploaded or imported, the stored original.
+/// Referenced originals are left where they are.
+#[delete("/media/{id}")]
+pub async fn delete_media(
+    state: web::Data<AppState>,
+    path: web::Path<Uuid>,
+) -> ApiResult<HttpResponse> {
+    let media_id = path.into_inner();
+    let thumbnails = list_thumbnails(&state.db_pool, media_id).await?;
+
+    let deleted = sqlx::query!(
+        r#"
+        DELETE FROM media m
+        WHERE m.id = $1
+        RETURNING m.file_path,
+                  EXISTS (
+                      SELECT 1 FROM media other
+                      WHERE other.file_path = m.file_path AND other.id <> m.id
+                  ) as "shared!"
+        "#,
+        media_id
+    )
+    .fetch_optional(&state.db_pool)
+    .await?
+    .ok_or_else(|| ApiError::NotFound(format!("Media not found: {}", media_id)))?;
+
+    // The row is gone, so a file that can't be removed is only worth a warning
+    let mut files: Vec<PathBuf> = thumbnails
+        .into_iter()
+        .map(|thumbnail| PathBuf::from(thumbnail.file_path))
+        .collect();
+    let original = PathBuf::from(&deleted.file_path);
+    if !deleted.shared && is_library_file(&state.config.storage, &original) {
+        files.push(original);
+    }
+    for file in files {
+        match tokio::fs::remove_file(&file).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Failed to remove {:?}: {}", file, e),
+        }
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Serve the stored thumbnail closest to the requested size.
+#[get("/media/{id}/thumbnail")]
+pub async fn get_thumbnail(
+    state: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    params: web::Query<ThumbnailParams>,
+) -> ApiResult<HttpResponse> {
+    let media_id = path.into_inner();
+    fetch_media(&state, media_id).await?;
+
+    let thumbnails = list_thumbnails(&state.db_pool, media_id).await?;
+    let thumbnail = select_thumbnail(&thumbnails, params.size)
+        .ok_or_else(|| ApiError::NotFound(format!("No thumbnails for media: {}", media_id)))?;
+
+    let bytes = match tokio::fs::read(&thumbnail.file_path).await {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(ApiError::NotFound(format!(
+                "Thumbnail file missing for media: {}",
+                media_id
+            )))
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    // A media item's content never changes, so its thumbnails rarely do
+    Ok(HttpResponse::Ok()
+        .content_type(thumbnail.content_type())
+        .insert_header(CacheControl(vec![
+            CacheDirective::Public,
+            CacheDirective::MaxAge(86_400),
+        ]))
+        .body(bytes))
+}
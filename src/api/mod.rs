@@ -1,11 +1,67 @@
+mod error;
+mod jobs;
+mod media;
+mod search;
+mod tags;
+#[cfg(test)]
+mod tests;
+
 use crate::core::state::AppState;
+use actix_web::{web, App, HttpServer};
+use error::ApiError;
 use std::error::Error;
+use tracing::info;
 
 pub async fn run_server(
     host: String,
     port: u16,
     app_state: AppState,
 ) -> Result<(), Box<dyn Error>> {
-    // TODO: Implement API server
-    todo!("Implement API server")
+    let app_state = web::Data::new(app_state);
+
+    let server =
+        HttpServer::new(move || App::new().app_data(app_state.clone()).configure(configure))
+            .bind((host.as_str(), port))?;
+
+    info!("API server listening on {}:{}", host, port);
+    server.run().await?;
+
+    Ok(())
+}
+
+/// Register the API routes and request extractor settings.
+fn configure(cfg: &mut web::ServiceConfig) {
+    cfg
+        // Report malformed requests as JSON 400s rather than actix's plain text defaults
+        .app_data(
+            web::PathConfig::default()
+                .error_handler(|err, _| ApiError::BadRequest(err.to_string()).into()),
+        )
+        .app_data(
+            web::QueryConfig::default()
+                .error_handler(|err, _| ApiError::BadRequest(err.to_string()).into()),
+        )
+        .app_data(
+            web::JsonConfig::default()
+                .error_handler(|err, _| ApiError::BadRequest(err.to_string()).into()),
+        )
+        .service(
+            web::scope("/api")
+                .service(media::upload_media)
+                .service(media::get_media)
+                .service(media::delete_media)
+                .service(media::get_thumbnail)
+                .service(tags::create_tag)
+                .service(tags::list_tags)
+                .service(tags::delete_tag)
+                .service(tags::get_media_tags)
+                .service(tags::add_media_tags)
+                .service(tags::remove_media_tag)
+                .service(search::search)
+                .service(search::similar)
+                .service(jobs::list_jobs)
+                .service(jobs::get_job)
+                .service(jobs::resume_job)
+                .service(jobs::cancel_job),
+        );
 }
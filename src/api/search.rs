@@ -0,0 +1,96 @@
+use super::error::{ApiError, ApiResult};
+use crate::core::embedding::TextTooLong;
+use crate::core::search::{self as core_search, SearchFilters, SearchQuery};
+use crate::core::state::AppState;
+use actix_web::{get, web, HttpResponse};
+use serde::Deserialize;
+use uuid::Uuid;
+
+const DEFAULT_LIMIT: i64 = 10;
+const MAX_LIMIT: i64 = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct SearchParams {
+    pub q: String,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+    pub min_similarity: Option<f64>,
+    /// Comma-separated list of tags the results must all carry
+    pub tags: Option<String>,
+    pub content_type: Option<String>,
+}
+
+/// Search the library. Responds with the hits and how the query was fitted
+/// into the text encoder's context:
+/// `{ "hits": [...], "query_fit": { "kind": "truncated", "tokens": 90, "limit": 77 } }`
+#[get("/search")]
+pub async fn search(
+    state: web::Data<AppState>,
+    params: web::Query<SearchParams>,
+) -> ApiResult<HttpResponse> {
+    let params = params.into_inner();
+
+    let text = params.q.trim();
+    if text.is_empty() {
+        return Err(ApiError::BadRequest(
+            "Query parameter 'q' cannot be empty".to_string(),
+        ));
+    }
+
+    let tags = params
+        .tags
+        .as_deref()
+        .map(|tags| {
+            tags.split(',')
+                .map(str::trim)
+                .filter(|tag| !tag.is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let query = SearchQuery {
+        limit: params.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT),
+        offset: params.offset.unwrap_or(0).max(0),
+        min_similarity: params.min_similarity,
+        filters: SearchFilters {
+            tags,
+            content_type: params.content_type,
+        },
+        ..SearchQuery::new(text)
+    };
+
+    let results = core_search::search(&query, &state).await.map_err(|e| {
+        match e.downcast::<TextTooLong>() {
+            Ok(too_long) => ApiError::BadRequest(too_long.to_string()),
+            Err(e) => ApiError::Internal(e),
+        }
+    })?;
+
+    Ok(HttpResponse::Ok().json(results))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SimilarParams {
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[get("/media/{id}/similar")]
+pub async fn similar(
+    state: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    params: web::Query<SimilarParams>,
+) -> ApiResult<HttpResponse> {
+    let media_id = path.into_inner();
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let offset = params.offset.unwrap_or(0).max(0);
+
+    let hits = core_search::find_similar_to_media(media_id, limit, offset, &state)
+        .await?
+        .ok_or_else(|| {
+            ApiError::NotFound(format!("No embedded media found with ID: {}", media_id))
+        })?;
+
+    Ok(HttpResponse::Ok().json(hits))
+}
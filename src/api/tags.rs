@@ -0,0 +1,70 @@
+use super::error::ApiResult;
+use crate::core::state::AppState;
+use crate::core::tags;
+use actix_web::{delete, get, post, web, HttpResponse};
+use serde::Deserialize;
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize)]
+pub struct CreateTagRequest {
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddMediaTagsRequest {
+    pub tags: Vec<String>,
+}
+
+#[post("/tags")]
+pub async fn create_tag(
+    state: web::Data<AppState>,
+    body: web::Json<CreateTagRequest>,
+) -> ApiResult<HttpResponse> {
+    let tag = tags::create_tag(&state.db_pool, &body.name).await?;
+    Ok(HttpResponse::Created().json(tag))
+}
+
+#[get("/tags")]
+pub async fn list_tags(state: web::Data<AppState>) -> ApiResult<HttpResponse> {
+    let tags = tags::list_tags_with_usage(&state.db_pool).await?;
+    Ok(HttpResponse::Ok().json(tags))
+}
+
+#[delete("/tags/{id}")]
+pub async fn delete_tag(
+    state: web::Data<AppState>,
+    path: web::Path<Uuid>,
+) -> ApiResult<HttpResponse> {
+    tags::delete_tag(&state.db_pool, path.into_inner()).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/media/{id}/tags")]
+pub async fn get_media_tags(
+    state: web::Data<AppState>,
+    path: web::Path<Uuid>,
+) -> ApiResult<HttpResponse> {
+    let tags = tags::get_media_tags(&state.db_pool, path.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(tags))
+}
+
+/// Associate tags with a media item, creating any tags that don't exist yet.
+#[post("/media/{id}/tags")]
+pub async fn add_media_tags(
+    state: web::Data<AppState>,
+    path: web::Path<Uuid>,
+    body: web::Json<AddMediaTagsRequest>,
+) -> ApiResult<HttpResponse> {
+    let tags = tags::update_media_tags(&state.db_pool, path.into_inner(), &body.tags, &[]).await?;
+    Ok(HttpResponse::Ok().json(tags))
+}
+
+#[delete("/media/{id}/tags/{tag_id}")]
+pub async fn remove_media_tag(
+    state: web::Data<AppState>,
+    path: web::Path<(Uuid, Uuid)>,
+) -> ApiResult<HttpResponse> {
+    let (media_id, tag_id) = path.into_inner();
+    tags::remove_media_tag(&state.db_pool, media_id, tag_id).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
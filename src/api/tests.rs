@@ -0,0 +1,422 @@
+//! End-to-end tests of ingest, search, tagging and deletion.
+//!
+//! These run against a throwaway database created by `#[sqlx::test]` from
+//! `DATABASE_URL`, which must point at a Postgres server with pgvector (the
+//! docker-compose service works), so they are ignored unless run with
+//! `--ignored`. Embeddings come from the fake backend, so no model weights
+//! are needed.
+
+use super::configure;
+use crate::core::config::{
+    Config, DatabaseConfig, EmbeddingBackend, EmbeddingConfig, IngestConfig, StorageConfig,
+};
+use crate::core::embedding::{load_embedder, Embedder, TextFit};
+use crate::core::failures::{self, FailureStage};
+use crate::core::jobs::{self, JobStatus};
+use crate::core::pipeline;
+use crate::core::search::{self as core_search, SearchQuery};
+use crate::core::state::AppState;
+use actix_web::http::StatusCode;
+use actix_web::test::{self, TestRequest};
+use actix_web::{web, App};
+use candle_core::{Device, Tensor};
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+use serde_json::{json, Value};
+use sqlx::postgres::PgPool;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use uuid::Uuid;
+
+const BOUNDARY: &str = "semantic-gallery-test-boundary";
+
+/// A scratch directory removed when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> Self {
+        let path = std::env::temp_dir().join(format!("semantic-gallery-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn test_state(pool: PgPool, media_path: &Path) -> AppState {
+    let config = Config {
+        database: DatabaseConfig {
+            host: "localhost".to_string(),
+            port: 5432,
+            username: String::new(),
+            password: String::new(),
+            database: String::new(),
+        },
+        embedding: EmbeddingConfig {
+            backend: EmbeddingBackend::Fake,
+            ..EmbeddingConfig::default()
+        },
+        storage: StorageConfig {
+            media_path: media_path.to_path_buf(),
+            thumbnail_sizes: vec![64],
+            library_layout: "{hash}.{ext}".to_string(),
+            library_roots: Vec::new(),
+        },
+        ingest: IngestConfig {
+            decode_workers: 2,
+            batch_size: 2,
+            ..IngestConfig::default()
+        },
+    };
+    let embedder = load_embedder(&config.embedding, Device::Cpu).unwrap();
+
+    AppState {
+        config,
+        db_pool: pool,
+        embedder,
+    }
+}
+
+/// Fails the first image batch it is given, then defers to `inner`.
+struct FailFirstBatch {
+    inner: Arc<dyn Embedder>,
+    failed: AtomicBool,
+}
+
+impl Embedder for FailFirstBatch {
+    fn model_id(&self) -> &str {
+        self.inner.model_id()
+    }
+
+    fn dimension(&self) -> usize {
+        self.inner.dimension()
+    }
+
+    fn encode_images(&self, images: &[DynamicImage]) -> anyhow::Result<Tensor> {
+        if !self.failed.swap(true, Ordering::SeqCst) {
+            anyhow::bail!("out of memory");
+        }
+        self.inner.encode_images(images)
+    }
+
+    fn encode_texts(&self, texts: &[&str]) -> anyhow::Result<(Tensor, Vec<TextFit>)> {
+        self.inner.encode_texts(texts)
+    }
+}
+
+/// Defers to `inner` but records its embeddings under another model name.
+struct RenamedModel {
+    inner: Arc<dyn Embedder>,
+    model_id: &'static str,
+}
+
+impl Embedder for RenamedModel {
+    fn model_id(&self) -> &str {
+        self.model_id
+    }
+
+    fn dimension(&self) -> usize {
+        self.inner.dimension()
+    }
+
+    fn encode_images(&self, images: &[DynamicImage]) -> anyhow::Result<Tensor> {
+        self.inner.encode_images(images)
+    }
+
+    fn encode_texts(&self, texts: &[&str]) -> anyhow::Result<(Tensor, Vec<TextFit>)> {
+        self.inner.encode_texts(texts)
+    }
+}
+
+/// An 8x8 PNG filled with a single colour.
+fn png(color: [u8; 3]) -> Vec<u8> {
+    let mut bytes = Cursor::new(Vec::new());
+    RgbImage::from_pixel(8, 8, Rgb(color))
+        .write_to(&mut bytes, ImageFormat::Png)
+        .unwrap();
+    bytes.into_inner()
+}
+
+fn multipart_body(files: &[(&str, Vec<u8>)]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (filename, bytes) in files {
+        body.extend(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\
+                 Content-Type: image/png\r\n\r\n",
+                BOUNDARY, filename
+            )
+            .into_bytes(),
+        );
+        body.extend(bytes);
+        body.extend(b"\r\n");
+    }
+    body.extend(format!("--{}--\r\n", BOUNDARY).into_bytes());
+    body
+}
+
+fn upload_request(files: &[(&str, Vec<u8>)]) -> TestRequest {
+    TestRequest::post()
+        .uri("/api/media")
+        .insert_header((
+            "content-type",
+            format!("multipart/form-data; boundary={}", BOUNDARY),
+        ))
+        .set_payload(multipart_body(files))
+}
+
+fn hit_ids(hits: &Value) -> Vec<&str> {
+    hits.as_array()
+        .unwrap()
+        .iter()
+        .map(|hit| hit["media_id"].as_str().unwrap())
+        .collect()
+}
+
+#[sqlx::test]
+#[ignore = "needs DATABASE_URL pointing at Postgres with pgvector"]
+async fn test_upload_tag_search_delete(pool: PgPool) {
+    let media_dir = TempDir::new();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(test_state(pool, media_dir.path())))
+            .configure(configure),
+    )
+    .await;
+
+    let request = upload_request(&[
+        ("red.png", png([255, 0, 0])),
+        ("blue.png", png([0, 0, 255])),
+    ]);
+    let response = test::call_service(&app, request.to_request()).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let uploaded: Vec<Value> = test::read_body_json(response).await;
+    assert_eq!(uploaded.len(), 2);
+    let red = uploaded[0]["id"].as_str().unwrap().to_string();
+    let blue = uploaded[1]["id"].as_str().unwrap().to_string();
+    assert_ne!(red, blue);
+
+    // The same content uploaded again resolves to the existing media item
+    let request = upload_request(&[("red-copy.png", png([255, 0, 0]))]);
+    let response = test::call_service(&app, request.to_request()).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let again: Vec<Value> = test::read_body_json(response).await;
+    assert_eq!(again[0]["id"].as_str(), Some(red.as_str()));
+
+    let request = TestRequest::post()
+        .uri(&format!("/api/media/{}/tags", red))
+        .set_json(json!({ "tags": ["Sunset"] }))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let request = TestRequest::get().uri("/api/search?q=sunset").to_request();
+    let results: Value = test::call_and_read_body_json(&app, request).await;
+    assert_eq!(hit_ids(&results["hits"]).first(), Some(&red.as_str()));
+    assert_eq!(results["hits"][0]["tier"], "exact_tag");
+    assert_eq!(results["query_fit"]["kind"], "whole");
+
+    let request = TestRequest::get()
+        .uri(&format!("/api/media/{}/similar", red))
+        .to_request();
+    let hits: Value = test::call_and_read_body_json(&app, request).await;
+    assert_eq!(hit_ids(&hits), vec![blue.as_str()]);
+
+    let red_file = PathBuf::from(uploaded[0]["file_path"].as_str().unwrap());
+    assert!(red_file.exists());
+
+    let request = TestRequest::delete()
+        .uri(&format!("/api/media/{}", red))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    // The upload belongs to the library, so it goes with the row
+    assert!(!red_file.exists());
+
+    let request = TestRequest::get()
+        .uri(&format!("/api/media/{}", red))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let request = TestRequest::get().uri("/api/search?q=sunset").to_request();
+    let results: Value = test::call_and_read_body_json(&app, request).await;
+    assert_eq!(hit_ids(&results["hits"]), vec![blue.as_str()]);
+}
+
+#[sqlx::test]
+#[ignore = "needs DATABASE_URL pointing at Postgres with pgvector"]
+async fn test_ingest_job_records_outcomes(pool: PgPool) {
+    let media_dir = TempDir::new();
+    let source_dir = TempDir::new();
+    let state = test_state(pool, media_dir.path());
+
+    let mut files = Vec::new();
+    for (name, bytes) in [
+        ("a-red.png", png([255, 0, 0])),
+        ("b-blue.png", png([0, 0, 255])),
+        ("c-red-copy.png", png([255, 0, 0])),
+        ("d-broken.png", b"not an image".to_vec()),
+    ] {
+        let path = source_dir.path().join(name);
+        std::fs::write(&path, bytes).unwrap();
+        files.push(path);
+    }
+
+    let job_id = jobs::create_job(&state.db_pool, "test", &files, &state.config.ingest)
+        .await
+        .unwrap();
+    let summary = jobs::run_job(job_id, &state, |_| {}).await.unwrap();
+
+    assert_eq!(summary.new, 2);
+    assert_eq!(summary.skipped, 1);
+    assert_eq!(summary.failed, 1);
+    assert_eq!(summary.failed_by_stage.get(&FailureStage::Decode), Some(&1));
+
+    let job = jobs::get_job(&state.db_pool, job_id).await.unwrap();
+    assert_eq!(job.status, JobStatus::Completed);
+    assert_eq!((job.done, job.total, job.failed), (4, 4, 1));
+
+    let failures = failures::list_failures(&state.db_pool).await.unwrap();
+    assert_eq!(failures.len(), 1);
+    assert!(failures[0].file_path.ends_with("d-broken.png"));
+    assert_eq!(failures[0].stage, FailureStage::Decode.as_str());
+
+    // A finished job can't be resumed
+    assert!(matches!(
+        jobs::run_job(job_id, &state, |_| {}).await,
+        Err(jobs::JobError::Finished(_, JobStatus::Completed))
+    ));
+}
+
+#[sqlx::test]
+#[ignore = "needs DATABASE_URL pointing at Postgres with pgvector"]
+async fn test_copy_of_failed_batch_is_ingested(pool: PgPool) {
+    let media_dir = TempDir::new();
+    let source_dir = TempDir::new();
+    let mut state = test_state(pool, media_dir.path());
+    state.embedder = Arc::new(FailFirstBatch {
+        inner: Arc::clone(&state.embedder),
+        failed: AtomicBool::new(false),
+    });
+
+    let mut files = Vec::new();
+    for name in ["a-red.png", "b-red-copy.png"] {
+        let path = source_dir.path().join(name);
+        std::fs::write(&path, png([255, 0, 0])).unwrap();
+        files.push(path);
+    }
+
+    // One decode worker and single-file batches, so the first batch holds
+    // only a-red.png and fails before the copy is looked at
+    let options = IngestConfig {
+        decode_workers: 1,
+        batch_size: 1,
+        ..IngestConfig::default()
+    };
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let summary = pipeline::run(files, &state, &options, cancelled, |_| {})
+        .await
+        .unwrap();
+
+    assert_eq!(summary.new, 1);
+    assert_eq!(summary.skipped, 0);
+    assert_eq!(summary.failed_by_stage.get(&FailureStage::Embed), Some(&1));
+
+    let stored: Vec<String> = sqlx::query_scalar!("SELECT file_path FROM media")
+        .fetch_all(&state.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(stored.len(), 1);
+    assert!(stored[0].ends_with("b-red-copy.png"));
+}
+
+#[sqlx::test]
+#[ignore = "needs DATABASE_URL pointing at Postgres with pgvector"]
+async fn test_switching_models_embeds_stored_media(pool: PgPool) {
+    let media_dir = TempDir::new();
+    let source_dir = TempDir::new();
+    let mut state = test_state(pool, media_dir.path());
+    let path = source_dir.path().join("red.png");
+    std::fs::write(&path, png([255, 0, 0])).unwrap();
+
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let options = state.config.ingest.clone();
+    let first = pipeline::run(
+        vec![path.clone()],
+        &state,
+        &options,
+        cancelled.clone(),
+        |_| {},
+    )
+    .await
+    .unwrap();
+    assert_eq!(first.new, 1);
+
+    state.embedder = Arc::new(RenamedModel {
+        inner: Arc::clone(&state.embedder),
+        model_id: "fake-v2",
+    });
+    let hits = core_search::search(&SearchQuery::new("red"), &state)
+        .await
+        .unwrap()
+        .hits;
+    assert!(hits.is_empty(), "vectors of another model were compared");
+
+    // Re-ingesting keeps the media item but embeds it with the new model
+    let second = pipeline::run(vec![path], &state, &options, cancelled, |_| {})
+        .await
+        .unwrap();
+    assert_eq!((second.new, second.skipped), (0, 1));
+
+    let models: Vec<String> =
+        sqlx::query_scalar!("SELECT model_name FROM embeddings ORDER BY model_name")
+            .fetch_all(&state.db_pool)
+            .await
+            .unwrap();
+    assert_eq!(models, vec!["fake", "fake-v2"]);
+    let hits = core_search::search(&SearchQuery::new("red"), &state)
+        .await
+        .unwrap()
+        .hits;
+    assert_eq!(hits.len(), 1);
+}
+
+#[sqlx::test]
+#[ignore = "needs DATABASE_URL pointing at Postgres with pgvector"]
+async fn test_running_job_is_not_resumed_twice(pool: PgPool) {
+    let media_dir = TempDir::new();
+    let state = test_state(pool, media_dir.path());
+    let files = [media_dir.path().join("photo.png")];
+    let job_id = jobs::create_job(&state.db_pool, "test", &files, &state.config.ingest)
+        .await
+        .unwrap();
+
+    // Another process holds a live lease on the job
+    let _lease = jobs::claim_job(&state.db_pool, job_id).await.unwrap();
+    assert!(matches!(
+        jobs::claim_job(&state.db_pool, job_id).await,
+        Err(jobs::JobError::Busy(_))
+    ));
+
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(state))
+            .configure(configure),
+    )
+    .await;
+    let request = TestRequest::post()
+        .uri(&format!("/api/jobs/{}/resume", job_id))
+        .to_request();
+    let response = test::call_service(&app, request).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+}
@@ -1,41 +1,190 @@
-use crate::core::ingest::process_image;
-use crate::core::media::extract_media_details_from_path;
+use crate::core::config::{Config, ImportMode};
+use crate::core::embedding::TextFit;
+use crate::core::failures;
+use crate::core::jobs::{self, JobStatus};
+use crate::core::media::{extract_media_details_from_path, sniff_format, MediaError};
+use crate::core::models;
+use crate::core::pipeline::FileResult;
+use crate::core::rescan::{self, RescanOptions};
+use crate::core::search::{self, SearchHit, SearchQuery};
 use crate::core::state::AppState;
+use crate::core::tags;
+use crate::core::thumbnails::{
+    find_missing_thumbnails, generate_thumbnails, remove_thumbnail_files, save_thumbnails,
+    Thumbnail,
+};
+use globset::{Glob, GlobSet, GlobSetBuilder};
 use indicatif;
+use sqlx::postgres::PgPool;
+use std::collections::HashSet;
 use std::error::Error;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+use uuid::Uuid;
+
+/// Options for the `ingest` command
+pub struct IngestOptions {
+    pub recursive: bool,
+    pub max_depth: Option<usize>,
+    /// Overrides `ingest.decode_workers` from the config
+    pub workers: Option<usize>,
+    /// Overrides `ingest.batch_size` from the config
+    pub batch_size: Option<usize>,
+    /// Overrides `ingest.import_mode` from the config
+    pub import_mode: Option<ImportMode>,
+    /// Skip the confirmation prompt
+    pub yes: bool,
+    /// Only ingest files matching one of these globs, relative to the path
+    pub include: Vec<String>,
+    /// Skip files and directories matching any of these globs
+    pub exclude: Vec<String>,
+    pub follow_symlinks: bool,
+    /// Reprocess files recorded as failed by earlier runs instead of walking
+    /// the path, which then only narrows the retry
+    pub retry_failed: bool,
+    /// Forget recorded failures for files that no longer exist
+    pub prune: bool,
+}
+
+/// Files found by `ingest`'s walk.
+#[derive(Default)]
+struct CollectedFiles {
+    images: Vec<PathBuf>,
+    /// Files that matched the filters but aren't a supported image, and why
+    skipped: Vec<(PathBuf, MediaError)>,
+}
+
+impl CollectedFiles {
+    /// Keep `path` if it sniffs as a supported image, otherwise note why not.
+    fn add(&mut self, path: PathBuf) {
+        match sniff_format(&path) {
+            Ok(_) => self.images.push(path),
+            Err(reason) => self.skipped.push((path, reason)),
+        }
+    }
+}
+
+/// How `ingest` walks a directory
+struct CollectOptions {
+    recursive: bool,
+    max_depth: usize,
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    follow_symlinks: bool,
+}
 
 // TODO: support either a single image or a directory
 pub async fn ingest(
-    path: PathBuf,
-    recursive: bool,
+    path: Option<PathBuf>,
+    options: IngestOptions,
     app_state: &AppState,
-    max_depth: Option<usize>,
 ) -> Result<(), Box<dyn Error>> {
-    let max_depth = max_depth.unwrap_or(5); // Default max depth of 5
-
-    // Collect all image files to process
-    let files = collect_image_files(&path, recursive, max_depth)?;
-
+    let files = if options.retry_failed {
+        failed_files(path.as_deref(), options.prune, &app_state.db_pool).await?
+    } else {
+        walked_files(path.as_deref(), &options)?
+    };
     if files.is_empty() {
-        println!("No image files found at path: {:?}", path);
+        report_nothing_to_ingest(path.as_deref(), &options);
         return Ok(());
     }
 
-    // Prompt user with count
     println!("Found {} image files to process.", files.len());
-    println!("Do you want to continue? [y/N]");
 
-    let mut input = String::new();
-    std::io::stdin().read_line(&mut input)?;
+    if !options.yes {
+        println!("Do you want to continue? [y/N]");
 
-    if !input.trim().eq_ignore_ascii_case("y") {
-        println!("Ingestion cancelled.");
+        let mut input = String::new();
+        std::io::stdin().read_line(&mut input)?;
+
+        if !input.trim().eq_ignore_ascii_case("y") {
+            println!("Ingestion cancelled.");
+            return Ok(());
+        }
+    }
+
+    let mut pipeline_config = app_state.config.ingest.clone();
+    if let Some(workers) = options.workers {
+        pipeline_config.decode_workers = workers;
+    }
+    if let Some(batch_size) = options.batch_size {
+        pipeline_config.batch_size = batch_size;
+    }
+    if let Some(import_mode) = options.import_mode {
+        pipeline_config.import_mode = import_mode;
+    }
+
+    let source = match &path {
+        Some(path) if !options.retry_failed => path.to_string_lossy().to_string(),
+        Some(path) => format!("retry failed under {}", path.display()),
+        None => "retry failed".to_string(),
+    };
+    let job_id = jobs::create_job(&app_state.db_pool, &source, &files, &pipeline_config).await?;
+    println!(
+        "Started job {}. If interrupted, continue it with `jobs resume {}`.",
+        job_id, job_id
+    );
+
+    run_job(job_id, app_state).await
+}
+
+/// List the files `ingest` would process without loading the model or
+/// changing the database. Only retrying failures connects to the database,
+/// to read them.
+pub async fn preview_ingest(
+    path: Option<PathBuf>,
+    options: IngestOptions,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let files = if options.retry_failed {
+        let pool = crate::core::db::create_pool(config).await?;
+        failed_files(path.as_deref(), false, &pool).await?
+    } else {
+        walked_files(path.as_deref(), &options)?
+    };
+    if files.is_empty() {
+        report_nothing_to_ingest(path.as_deref(), &options);
         return Ok(());
     }
 
+    for file in &files {
+        println!("{}", file.display());
+    }
+    println!("Would ingest {} image files.", files.len());
+    if options.retry_failed && options.prune {
+        println!("Dry run, missing files were not forgotten.");
+    }
+    Ok(())
+}
+
+/// The images under the ingest path, after reporting the files skipped as
+/// unsupported.
+fn walked_files(
+    path: Option<&Path>,
+    options: &IngestOptions,
+) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let path = path.ok_or("A path is required unless --retry-failed is given")?;
+    let collected = collect_ingest_files(path, options)?;
+    report_skipped(&collected.skipped);
+    Ok(collected.images)
+}
+
+fn report_nothing_to_ingest(path: Option<&Path>, options: &IngestOptions) {
+    match path {
+        Some(path) if !options.retry_failed => {
+            println!("No image files found at path: {:?}", path)
+        }
+        _ => println!("No failed files to retry."),
+    }
+}
+
+/// Run a job with a progress bar that starts from the work already done by
+/// earlier runs.
+async fn run_job(job_id: Uuid, app_state: &AppState) -> Result<(), Box<dyn Error>> {
+    let job = jobs::get_job(&app_state.db_pool, job_id).await?;
+
     // Process each file with a progress bar
-    let progress_bar = indicatif::ProgressBar::new(files.len() as u64);
+    let progress_bar = indicatif::ProgressBar::new(job.total as u64);
     progress_bar.set_style(
         indicatif::ProgressStyle::default_bar()
             .template(
@@ -44,142 +193,573 @@ pub async fn ingest(
             .unwrap()
             .progress_chars("#>-"),
     );
+    progress_bar.set_position(job.done as u64);
+    progress_bar.reset_eta();
 
-    for file_path in files {
-        // Extract details and process the image
-        match extract_media_details_from_path(&file_path) {
-            Ok(media_details) => {
-                if let Err(e) = process_image(media_details, &app_state).await {
-                    eprintln!("Error processing {:?}: {}", file_path, e);
-                }
-            }
-            Err(e) => {
-                eprintln!("Error extracting details from {:?}: {}", file_path, e);
-            }
+    let summary = jobs::run_job(job_id, app_state, |result| {
+        if let FileResult::Failed { path, stage, error } = result {
+            progress_bar.println(format!("[{}] {:?}: {}", stage, path, error));
         }
-
         progress_bar.inc(1);
+    })
+    .await?;
+
+    let job = jobs::get_job(&app_state.db_pool, job_id).await?;
+    if job.status == JobStatus::Cancelled {
+        progress_bar.abandon_with_message("Job cancelled");
+        println!(
+            "Job {} was cancelled with {} of {} files processed.",
+            job_id, job.done, job.total
+        );
+    } else {
+        progress_bar.finish_with_message("Ingestion complete!");
+    }
+    println!(
+        "New: {}, updated (moved): {}, skipped (unchanged): {}, failed: {}",
+        summary.new, summary.moved, summary.skipped, summary.failed
+    );
+    if summary.failed > 0 {
+        let by_stage: Vec<String> = summary
+            .failed_by_stage
+            .iter()
+            .map(|(stage, count)| format!("{}: {}", stage, count))
+            .collect();
+        println!("Failures by stage: {}", by_stage.join(", "));
+        println!("Run `ingest --retry-failed` to retry them.");
+    }
+    Ok(())
+}
+
+pub async fn list_jobs(app_state: &AppState) -> Result<(), Box<dyn Error>> {
+    let jobs = jobs::list_jobs(&app_state.db_pool).await?;
+
+    if jobs.is_empty() {
+        println!("No ingest jobs found.");
+        return Ok(());
+    }
+
+    for job in jobs {
+        let created = job
+            .created_at
+            .map(|created| created.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+        println!(
+            "{} {:<9} {:>6}/{:<6} failed: {:<5} {} {}",
+            job.id, job.status, job.done, job.total, job.failed, created, job.source
+        );
     }
 
-    progress_bar.finish_with_message("Ingestion complete!");
     Ok(())
 }
 
-fn collect_image_files(
-    path: &PathBuf,
-    recursive: bool,
-    max_depth: usize,
+pub async fn resume_job(job_id: Uuid, app_state: &AppState) -> Result<(), Box<dyn Error>> {
+    let job = jobs::get_job(&app_state.db_pool, job_id).await?;
+    if !job.status.is_resumable() {
+        return Err(format!("Job {} is already {}", job_id, job.status).into());
+    }
+
+    println!(
+        "Resuming job {} ({} of {} files already processed).",
+        job_id, job.done, job.total
+    );
+    run_job(job_id, app_state).await
+}
+
+pub async fn cancel_job(job_id: Uuid, app_state: &AppState) -> Result<(), Box<dyn Error>> {
+    let job = jobs::cancel_job(&app_state.db_pool, job_id).await?;
+    println!(
+        "Cancelled job {} with {} of {} files processed.",
+        job.id, job.done, job.total
+    );
+    Ok(())
+}
+
+/// List files passed over by the walk, so formats such as HEIC or RAW don't
+/// silently go missing from the library.
+fn report_skipped(skipped: &[(PathBuf, MediaError)]) {
+    if skipped.is_empty() {
+        return;
+    }
+
+    println!(
+        "Skipping {} files that are not supported images:",
+        skipped.len()
+    );
+    for (path, reason) in skipped {
+        println!("  {}: {}", path.display(), reason);
+    }
+}
+
+/// Walk the ingest path, applying the include/exclude and symlink options.
+fn collect_ingest_files(
+    path: &Path,
+    options: &IngestOptions,
+) -> Result<CollectedFiles, Box<dyn Error>> {
+    let collect_options = CollectOptions {
+        recursive: options.recursive,
+        max_depth: options.max_depth.unwrap_or(5), // Default max depth of 5
+        include: if options.include.is_empty() {
+            None
+        } else {
+            Some(build_globset(&options.include)?)
+        },
+        exclude: build_globset(&options.exclude)?,
+        follow_symlinks: options.follow_symlinks,
+    };
+
+    collect_image_files(path, &collect_options)
+}
+
+/// Files recorded as failed, optionally limited to those under `path`.
+///
+/// Files that no longer exist are listed and left recorded, in case their
+/// drive is just unmounted, unless `prune` is set.
+async fn failed_files(
+    path: Option<&Path>,
+    prune: bool,
+    pool: &PgPool,
 ) -> Result<Vec<PathBuf>, Box<dyn Error>> {
-    let mut files = Vec::new();
+    // Failures are recorded under canonical paths, so match against one
+    let path = path.map(failures::failure_path);
+    let (existing, gone): (Vec<PathBuf>, Vec<PathBuf>) = failures::list_failures(pool)
+        .await?
+        .into_iter()
+        .map(|failure| PathBuf::from(failure.file_path))
+        .filter(|file| path.as_ref().map_or(true, |path| file.starts_with(path)))
+        .partition(|file| file.exists());
 
-    if path.is_file() {
-        // If path is a file, just return it if it's an image
-        if is_image_file(path) {
-            files.push(path.clone());
+    if !gone.is_empty() {
+        println!("{} failed files no longer exist:", gone.len());
+        for file in &gone {
+            println!("  {}", file.display());
         }
+
+        if prune {
+            let gone: Vec<String> = gone
+                .iter()
+                .map(|file| file.to_string_lossy().to_string())
+                .collect();
+            failures::clear_failures(pool, &gone).await?;
+            println!("Forgot {} missing files.", gone.len());
+        } else {
+            println!("Pass --prune to forget them.");
+        }
+    }
+
+    Ok(existing)
+}
+
+/// Keep the library in sync with a directory until interrupted.
+pub async fn watch(path: PathBuf, state: &AppState) -> Result<(), Box<dyn Error>> {
+    println!("Watching {:?} for changes. Press Ctrl-C to stop.", path);
+
+    tokio::select! {
+        result = crate::core::watch::watch(&path, state) => result?,
+        _ = tokio::signal::ctrl_c() => println!("Stopped watching."),
+    }
+
+    Ok(())
+}
+
+pub async fn rescan(
+    options: RescanOptions,
+    dry_run: bool,
+    state: &AppState,
+) -> Result<(), Box<dyn Error>> {
+    let roots = rescan::library_roots(state);
+    println!("Scanning {:?}...", roots);
+    let report = rescan::scan(state).await?;
+
+    for missing in &report.missing {
+        println!("Missing: {} ({})", missing.file_path, missing.media_id);
+    }
+    for moved in &report.moved {
+        println!("Moved:   {} -> {:?}", moved.from, moved.to);
+    }
+    for changed in &report.changed {
+        println!("Changed: {:?} ({})", changed.path, changed.media_id);
+    }
+    for path in &report.new {
+        println!("New:     {:?}", path);
+    }
+    for (path, error) in &report.errors {
+        println!("Error:   {:?}: {}", path, error);
+    }
+
+    println!(
+        "Unchanged: {}, missing: {}, moved: {}, changed: {}, new: {}, duplicates: {}, errors: {}",
+        report.unchanged,
+        report.missing.len(),
+        report.moved.len(),
+        report.changed.len(),
+        report.new.len(),
+        report.duplicates.len(),
+        report.errors.len()
+    );
+
+    if dry_run {
+        println!("Dry run, no changes made.");
+        return Ok(());
+    }
+
+    let summary = rescan::apply(&report, options, state).await?;
+    for (path, error) in &summary.failed {
+        println!("Failed:  {:?}: {}", path, error);
+    }
+    println!(
+        "Refreshed: {}, marked duplicate: {}, relocated: {}, marked missing: {}, pruned: {}, \
+         re-embedded: {}, failed: {}",
+        summary.refreshed,
+        summary.marked_duplicate,
+        summary.relocated,
+        summary.marked_missing,
+        summary.pruned,
+        summary.reembedded,
+        summary.failed.len()
+    );
+    if !report.changed.is_empty() && !options.reembed {
+        println!("Run with --reembed to update changed files.");
+    }
+    if !report.new.is_empty() {
+        println!("Run `ingest` to add new files.");
+    }
+
+    Ok(())
+}
+
+fn build_globset(patterns: &[String]) -> Result<GlobSet, Box<dyn Error>> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = Glob::new(pattern).map_err(|e| format!("Invalid glob {:?}: {}", pattern, e))?;
+        builder.add(glob);
+    }
+    Ok(builder.build()?)
+}
+
+fn collect_image_files(
+    path: &Path,
+    options: &CollectOptions,
+) -> Result<CollectedFiles, Box<dyn Error>> {
+    let mut files = CollectedFiles::default();
+
+    if path.is_file() {
+        files.add(path.to_path_buf());
     } else if path.is_dir() {
-        // For directories, collect all image files
-        collect_images_from_dir(path, &mut files, recursive, max_depth, 0)?;
+        // For directories, collect all image files. A root that can't be
+        // resolved is still walked, it just isn't guarded against cycles.
+        let mut visited: HashSet<PathBuf> = path.canonicalize().into_iter().collect();
+        collect_images_from_dir(path, path, &mut files, options, 0, &mut visited)?;
     } else {
         return Err(format!("Path does not exist: {:?}", path).into());
     }
 
+    files.images.sort();
+    files.skipped.sort_by(|(a, _), (b, _)| a.cmp(b));
     Ok(files)
 }
 
+/// Collect images below `dir`, matching globs against paths relative to
+/// `root`. When following symlinks, `visited` holds the canonical path of
+/// every directory entered so link cycles are only walked once.
+///
+/// Links to files are always collected; linked directories are only entered
+/// with `follow_symlinks`. Entries that can't be read, including dangling
+/// links, are logged and skipped rather than aborting the walk.
 fn collect_images_from_dir(
-    dir: &PathBuf,
-    files: &mut Vec<PathBuf>,
-    recursive: bool,
-    max_depth: usize,
+    root: &Path,
+    dir: &Path,
+    files: &mut CollectedFiles,
+    options: &CollectOptions,
     current_depth: usize,
+    visited: &mut HashSet<PathBuf>,
 ) -> Result<(), Box<dyn Error>> {
-    if current_depth > max_depth {
+    if current_depth > options.max_depth {
         return Ok(());
     }
 
-    for entry in std::fs::read_dir(dir)? {
-        let entry = entry?;
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if current_depth > 0 => {
+            warn!("Skipping unreadable directory {:?}: {}", dir, e);
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("Skipping unreadable entry in {:?}: {}", dir, e);
+                continue;
+            }
+        };
         let path = entry.path();
+        let relative = path.strip_prefix(root).unwrap_or(&path);
 
-        if path.is_file() && is_image_file(&path) {
-            files.push(path);
-        } else if recursive && path.is_dir() {
-            collect_images_from_dir(&path, files, recursive, max_depth, current_depth + 1)?;
+        if options.exclude.is_match(relative) {
+            continue;
+        }
+
+        let file_type = match entry.file_type() {
+            Ok(file_type) => file_type,
+            Err(e) => {
+                warn!("Skipping {:?}: {}", path, e);
+                continue;
+            }
+        };
+        let is_link = file_type.is_symlink();
+        let file_type = if is_link {
+            match std::fs::metadata(&path) {
+                Ok(metadata) => metadata.file_type(),
+                Err(e) => {
+                    warn!("Skipping broken link {:?}: {}", path, e);
+                    continue;
+                }
+            }
+        } else {
+            file_type
+        };
+
+        if file_type.is_file() {
+            let included = options
+                .include
+                .as_ref()
+                .map_or(true, |include| include.is_match(relative));
+            if included {
+                files.add(path);
+            }
+        } else if options.recursive && file_type.is_dir() {
+            if is_link && !options.follow_symlinks {
+                continue;
+            }
+            if options.follow_symlinks {
+                let canonical = match path.canonicalize() {
+                    Ok(canonical) => canonical,
+                    Err(e) => {
+                        warn!("Skipping {:?}: {}", path, e);
+                        continue;
+                    }
+                };
+                if !visited.insert(canonical) {
+                    continue;
+                }
+            }
+            collect_images_from_dir(root, &path, files, options, current_depth + 1, visited)?;
         }
     }
 
     Ok(())
 }
 
-fn is_image_file(path: &PathBuf) -> bool {
-    if let Some(ext) = path.extension() {
-        let ext_str = ext.to_string_lossy().to_lowercase();
-        matches!(
-            ext_str.as_str(),
-            "jpg" | "jpeg" | "png" | "gif" | "webp" | "tiff" | "bmp"
-        )
-    } else {
-        false
-    }
-}
-
-pub async fn search(query: String, limit: usize, state: &AppState) -> Result<(), Box<dyn Error>> {
-    // Generate text embedding for the query
-    let text_embedding = state.embedder.encode_text(&query)?;
-    let embedding_vec = text_embedding.flatten_all()?.to_vec1::<f32>()?;
-
-    // TODO: move to a core function and make implementation agnostic
-
-    // TODO: add debug/trace logging for time taken to search
-    // Search for similar images in the database using vector similarity
-    let results = sqlx::query!(
-        r#"
-        SELECT m.id, m.filename, m.file_path, 
-               1 - (e.embedding <=> $1::vector) as similarity
-        FROM media m
-        JOIN embeddings e ON m.id = e.media_id
-        ORDER BY similarity DESC
-        LIMIT $2
-        "#,
-        &embedding_vec as &[f32],
-        limit as i64
-    )
-    .fetch_all(&state.db_pool)
-    .await?;
+pub async fn search(query: SearchQuery, state: &AppState) -> Result<(), Box<dyn Error>> {
+    let results = search::search(&query, state).await?;
+
+    match results.query_fit {
+        TextFit::Whole => {}
+        TextFit::Truncated { tokens, limit } => println!(
+            "Note: the query is {} tokens long, only the first {} were searched",
+            tokens, limit
+        ),
+        TextFit::Chunked { tokens, windows } => println!(
+            "Note: the query is {} tokens long and was searched as {} averaged windows",
+            tokens, windows
+        ),
+    }
 
     // Display the results
-    if results.is_empty() {
-        println!("No results found for query: \"{}\"", query);
+    if results.hits.is_empty() {
+        println!("No results found for query: \"{}\"", query.text);
     } else {
-        println!("Search results for: \"{}\"", query);
-        println!("{:-<50}", "");
-
-        for (i, result) in results.iter().enumerate() {
-            let similarity_percentage = result.similarity.map(|s| s * 100.0).unwrap_or(0.0);
-            print!(
-                "{}. {} (ID: {})\n   Path: file://{}\n   Similarity: {:.2}%\n",
-                i + 1,
-                result.filename,
-                result.id,
-                result.file_path,
-                similarity_percentage
-            );
+        println!("Search results for: \"{}\"", query.text);
+        print_hits(&results.hits, query.offset);
+    }
+
+    Ok(())
+}
+
+/// Find media similar to an existing media item (by ID) or an image file.
+pub async fn similar(
+    target: String,
+    limit: i64,
+    offset: i64,
+    state: &AppState,
+) -> Result<(), Box<dyn Error>> {
+    let results = match Uuid::parse_str(target.trim()) {
+        Ok(media_id) => search::find_similar_to_media(media_id, limit, offset, state)
+            .await?
+            .ok_or_else(|| format!("No embedded media found with ID: {}", media_id))?,
+        Err(_) => {
+            let path = PathBuf::from(&target);
+            if !path.is_file() {
+                return Err(format!("{:?} is neither a media ID nor an image file", target).into());
+            }
+            let media_details = extract_media_details_from_path(&path)?;
+            search::find_similar_to_image(media_details, limit, offset, state).await?
         }
+    };
+
+    if results.is_empty() {
+        println!("No similar media found for: {}", target);
+    } else {
+        println!("Media similar to: {}", target);
+        print_hits(&results, offset);
     }
 
     Ok(())
 }
 
+fn print_hits(results: &[SearchHit], offset: i64) {
+    println!("{:-<50}", "");
+
+    for (i, result) in results.iter().enumerate() {
+        print!(
+            "{}. {} (ID: {})\n   Path: file://{}\n   Similarity: {:.2}% (matched by {})\n",
+            offset as usize + i + 1,
+            result.filename,
+            result.media_id,
+            result.file_path,
+            result.similarity * 100.0,
+            result.tier
+        );
+        if let Some(captured_at) = result.captured_at {
+            println!("   Taken: {}", captured_at.format("%Y-%m-%d %H:%M:%S"));
+        }
+        if let (Some(latitude), Some(longitude)) = (result.latitude, result.longitude) {
+            println!("   Location: {:.5}, {:.5}", latitude, longitude);
+        }
+    }
+}
+
 pub async fn tag(
     media_id: String,
     add: Vec<String>,
     remove: Vec<String>,
     state: &AppState,
 ) -> Result<(), Box<dyn Error>> {
-    todo!("Implement tag management")
+    let media_id = Uuid::parse_str(media_id.trim())
+        .map_err(|e| format!("Invalid media ID {:?}: {}", media_id, e))?;
+
+    let tags = if add.is_empty() && remove.is_empty() {
+        tags::get_media_tags(&state.db_pool, media_id).await?
+    } else {
+        tags::update_media_tags(&state.db_pool, media_id, &add, &remove).await?
+    };
+
+    if tags.is_empty() {
+        println!("Media {} has no tags.", media_id);
+    } else {
+        let names: Vec<&str> = tags.iter().map(|tag| tag.name.as_str()).collect();
+        println!("Tags for media {}: {}", media_id, names.join(", "));
+    }
+
+    Ok(())
 }
 
 pub async fn list_tags(state: &AppState) -> Result<(), Box<dyn Error>> {
-    todo!("Implement tag listing")
+    let tags = tags::list_tags_with_usage(&state.db_pool).await?;
+
+    if tags.is_empty() {
+        println!("No tags found.");
+        return Ok(());
+    }
+
+    println!("{:<30} {:>10}", "Tag", "Media");
+    println!("{:-<41}", "");
+    for tag in tags {
+        println!("{:<30} {:>10}", tag.name, tag.media_count);
+    }
+
+    Ok(())
+}
+
+pub async fn regenerate_thumbnails(force: bool, state: &AppState) -> Result<(), Box<dyn Error>> {
+    let storage = state.config.storage.clone();
+    let missing = find_missing_thumbnails(&state.db_pool, &storage.thumbnail_sizes, force).await?;
+
+    if missing.is_empty() {
+        println!("All thumbnails are up to date.");
+        return Ok(());
+    }
+
+    let progress_bar = indicatif::ProgressBar::new(missing.len() as u64);
+    progress_bar.set_style(
+        indicatif::ProgressStyle::default_bar()
+            .template(
+                "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})",
+            )
+            .unwrap()
+            .progress_chars("#>-"),
+    );
+
+    let mut regenerated = 0;
+    let mut failed = 0;
+    for media in missing {
+        let storage = storage.clone();
+        let file_path = media.file_path.clone();
+        let generated = tokio::task::spawn_blocking(move || -> Result<Vec<Thumbnail>, String> {
+            let details = extract_media_details_from_path(&PathBuf::from(&file_path))
+                .map_err(|e| format!("Error reading image: {}", e))?;
+            if force {
+                remove_thumbnail_files(
+                    &storage.media_path,
+                    &details.content_hash,
+                    &storage.thumbnail_sizes,
+                );
+            }
+            generate_thumbnails(&details.image, &details.content_hash, &storage)
+                .map_err(|e| format!("Error generating thumbnails: {}", e))
+        })
+        .await?;
+
+        let saved = match generated {
+            Ok(thumbnails) => {
+                let mut conn = state.db_pool.acquire().await?;
+                save_thumbnails(&mut conn, media.media_id, &thumbnails)
+                    .await
+                    .map_err(|e| format!("Error saving to database: {}", e))
+            }
+            Err(e) => Err(e),
+        };
+
+        match saved {
+            Ok(()) => regenerated += 1,
+            Err(e) => {
+                failed += 1;
+                progress_bar.println(format!("{} ({}): {}", media.media_id, media.file_path, e));
+            }
+        }
+        progress_bar.inc(1);
+    }
+
+    progress_bar.finish_and_clear();
+    println!("Regenerated: {}, failed: {}", regenerated, failed);
+    Ok(())
+}
+
+/// Pre-populate the Hugging Face cache with the configured model.
+pub async fn fetch_models(config: &Config) -> Result<(), Box<dyn Error>> {
+    let embedding = config.embedding.clone();
+    let Some(model_id) = models::hub_model_id(&embedding) else {
+        println!(
+            "The {:?} embedding backend needs no model files.",
+            embedding.backend
+        );
+        return Ok(());
+    };
+
+    let revision = models::hub_revision(&embedding, &model_id);
+    println!("Fetching {} ({})...", model_id, revision);
+    let files =
+        tokio::task::spawn_blocking(move || models::fetch_model_files(&embedding)).await??;
+    println!("Weights:   {}", files.weights.display());
+    println!("Tokenizer: {}", files.tokenizer.display());
+
+    if config.embedding.model_path.is_some() || config.embedding.tokenizer_path.is_some() {
+        println!(
+            "Note: embedding.model_path/tokenizer_path are set and take precedence over the cache."
+        );
+    }
+
+    Ok(())
 }
@@ -1,8 +1,13 @@
+use crate::core::embedding::ClipVariant;
+#[cfg(test)]
+use crate::core::fake;
+use crate::core::siglip::{self, SiglipEmbedder};
 use anyhow::Result;
 use config::{Config as ConfigSource, File};
 use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::path::PathBuf;
+use std::str::FromStr;
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Config {
@@ -10,6 +15,8 @@ pub struct Config {
     #[serde(default)]
     pub embedding: EmbeddingConfig,
     pub storage: StorageConfig,
+    #[serde(default)]
+    pub ingest: IngestConfig,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -23,17 +30,174 @@ pub struct DatabaseConfig {
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct EmbeddingConfig {
+    /// Model family used to embed images and queries
+    #[serde(default)]
+    pub backend: EmbeddingBackend,
+    /// CLIP architecture of the weights at `model_path`. Only used by the
+    /// `clip` backend.
+    #[serde(default)]
+    pub model: ClipVariant,
+    /// Expected embedding dimension. Defaults to the model's output dimension
+    /// and must match both the model and the `embeddings.embedding` column.
+    #[serde(default)]
+    pub dimension: Option<usize>,
+    /// Hugging Face hub repository to load the model from, such as
+    /// "openai/clip-vit-base-patch32". Defaults to the reference weights of
+    /// the backend.
+    #[serde(default)]
+    pub model_id: Option<String>,
+    /// Branch, tag or commit of `model_id`. Defaults to a revision known to
+    /// have `model.safetensors`, see [`crate::core::models::hub_revision`].
+    #[serde(default)]
+    pub revision: Option<String>,
+    /// Local weights, used instead of the hub model when set
     pub model_path: Option<String>,
+    /// Local tokenizer, used instead of the hub model's when set
     pub tokenizer_path: Option<String>,
+    /// Overrides of the image preprocessing in the model's
+    /// `preprocessor_config.json`
+    #[serde(default)]
+    pub preprocessing: PreprocessingConfig,
+    /// What to do with text longer than the model's context
+    #[serde(default)]
+    pub long_text: LongTextMode,
+    /// Legacy switch used when `device` isn't set: `true` means `auto`,
+    /// `false` means `cpu`
     pub use_gpu: bool,
+    /// Device to run inference on: "cpu", "cuda", "cuda:<ordinal>" or "auto"
+    #[serde(default)]
+    pub device: Option<DeviceSetting>,
+}
+
+/// The embedding model implementations that can be selected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmbeddingBackend {
+    /// OpenAI CLIP, in the architecture given by `embedding.model`
+    #[default]
+    Clip,
+    /// Google SigLIP base, patch 16, 224px
+    Siglip,
+    /// Hash-based embeddings so tests run without model weights. Only
+    /// available in test builds.
+    #[cfg(test)]
+    Fake,
+}
+
+/// How text that doesn't fit the text encoder's context is handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LongTextMode {
+    /// Keep the start of the text and drop the rest
+    #[default]
+    Truncate,
+    /// Reject the text
+    Error,
+    /// Embed the text in context-sized windows and average them
+    Chunk,
+}
+
+/// Image preprocessing settings that take precedence over the model's own.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct PreprocessingConfig {
+    /// Length the shortest edge is resized to
+    pub shortest_edge: Option<u32>,
+    /// Side of the square cut from the centre of the resized image
+    pub crop_size: Option<u32>,
+    /// Per-channel mean subtracted after scaling pixels to [0, 1]
+    pub image_mean: Option<[f32; 3]>,
+    /// Per-channel standard deviation divided by after subtracting the mean
+    pub image_std: Option<[f32; 3]>,
+}
+
+/// Where to run model inference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum DeviceSetting {
+    Cpu,
+    Cuda(usize),
+    /// CUDA device 0 if available, otherwise CPU
+    Auto,
+}
+
+impl TryFrom<String> for DeviceSetting {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.trim().to_lowercase().as_str() {
+            "cpu" => Ok(DeviceSetting::Cpu),
+            "auto" => Ok(DeviceSetting::Auto),
+            "cuda" | "gpu" => Ok(DeviceSetting::Cuda(0)),
+            other => other
+                .strip_prefix("cuda:")
+                .and_then(|ordinal| ordinal.parse().ok())
+                .map(DeviceSetting::Cuda)
+                .ok_or_else(|| {
+                    format!(
+                        "Invalid device {:?}, expected \"cpu\", \"cuda\", \"cuda:<ordinal>\" or \"auto\"",
+                        value
+                    )
+                }),
+        }
+    }
+}
+
+impl From<DeviceSetting> for String {
+    fn from(value: DeviceSetting) -> Self {
+        match value {
+            DeviceSetting::Cpu => "cpu".to_string(),
+            DeviceSetting::Cuda(ordinal) => format!("cuda:{}", ordinal),
+            DeviceSetting::Auto => "auto".to_string(),
+        }
+    }
+}
+
+impl EmbeddingConfig {
+    /// The device to use, falling back to `use_gpu` when `device` isn't set
+    pub fn device_setting(&self) -> DeviceSetting {
+        self.device.unwrap_or(if self.use_gpu {
+            DeviceSetting::Auto
+        } else {
+            DeviceSetting::Cpu
+        })
+    }
+
+    /// Name of the configured model, as recorded with its embeddings
+    pub fn model_name(&self) -> &'static str {
+        match self.backend {
+            EmbeddingBackend::Clip => self.model.model_name(),
+            EmbeddingBackend::Siglip => siglip::MODEL_ID,
+            #[cfg(test)]
+            EmbeddingBackend::Fake => fake::MODEL_ID,
+        }
+    }
+
+    /// Dimension of the embeddings the configured model produces
+    pub fn model_dimension(&self) -> usize {
+        match self.backend {
+            EmbeddingBackend::Clip => self.model.dimension(),
+            EmbeddingBackend::Siglip => SiglipEmbedder::model_dimension(),
+            // The fake embedder produces whatever dimension it is asked for
+            #[cfg(test)]
+            EmbeddingBackend::Fake => self.dimension.unwrap_or(fake::DEFAULT_DIMENSION),
+        }
+    }
 }
 
 impl Default for EmbeddingConfig {
     fn default() -> Self {
         Self {
+            backend: EmbeddingBackend::default(),
+            model: ClipVariant::default(),
+            dimension: None,
+            model_id: None,
+            revision: None,
             model_path: None,
             tokenizer_path: None,
+            preprocessing: PreprocessingConfig::default(),
+            long_text: LongTextMode::default(),
             use_gpu: false,
+            device: None,
         }
     }
 }
@@ -41,6 +205,85 @@ impl Default for EmbeddingConfig {
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct StorageConfig {
     pub media_path: PathBuf,
+    /// Longest-edge bounds, in pixels, of the thumbnails written on ingest
+    #[serde(default = "default_thumbnail_sizes")]
+    pub thumbnail_sizes: Vec<u32>,
+    /// Path, relative to `media_path`, that imported originals are stored at.
+    /// See [`crate::core::library::render_layout`] for the placeholders.
+    #[serde(default = "default_library_layout")]
+    pub library_layout: String,
+    /// Directories of referenced originals that `rescan` walks, in addition
+    /// to `media_path`
+    #[serde(default)]
+    pub library_roots: Vec<PathBuf>,
+}
+
+fn default_thumbnail_sizes() -> Vec<u32> {
+    vec![256, 1024]
+}
+
+fn default_library_layout() -> String {
+    "{year}/{month}/{hash}.{ext}".to_string()
+}
+
+/// What ingestion does with the original files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportMode {
+    /// Copy originals into the library, leaving the source untouched
+    Copy,
+    /// Copy originals into the library, then delete the source once stored
+    Move,
+    /// Leave originals where they are and store their current path
+    #[default]
+    Reference,
+}
+
+impl FromStr for ImportMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "copy" => Ok(ImportMode::Copy),
+            "move" => Ok(ImportMode::Move),
+            "reference" => Ok(ImportMode::Reference),
+            _ => Err(format!(
+                "Invalid import mode {:?}, expected \"copy\", \"move\" or \"reference\"",
+                value
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct IngestConfig {
+    /// Number of blocking threads decoding images in parallel
+    pub decode_workers: usize,
+    /// Number of images embedded per model forward pass
+    pub batch_size: usize,
+    /// Number of images buffered between pipeline stages
+    pub channel_capacity: usize,
+    /// Whether originals are copied or moved into the library, or referenced
+    /// in place
+    pub import_mode: ImportMode,
+    /// How long a watched file must be quiet before it is ingested, so
+    /// partially written files aren't picked up
+    pub watch_debounce_ms: u64,
+}
+
+impl Default for IngestConfig {
+    fn default() -> Self {
+        Self {
+            decode_workers: std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(4),
+            batch_size: 16,
+            channel_capacity: 64,
+            import_mode: ImportMode::default(),
+            watch_debounce_ms: 2000,
+        }
+    }
 }
 
 /// Load the configuration from the environment variables and the config file.
@@ -73,17 +316,89 @@ fn validate_config(config: &Config) -> Result<(), Box<dyn Error>> {
         return Err("Database host cannot be empty".into());
     }
 
-    if !config.embedding.model_path.is_some() {
-        return Err("Embedding model path must be provided".into());
+    if let Some(std) = config.embedding.preprocessing.image_std {
+        if std.iter().any(|&value| value <= 0.0) {
+            return Err("Preprocessing image_std values must be greater than zero".into());
+        }
     }
 
-    if !config.embedding.tokenizer_path.is_some() {
-        return Err("Embedding tokenizer path must be provided".into());
+    if let Some(revision) = &config.embedding.revision {
+        if revision.trim().is_empty() {
+            return Err("Embedding model revision cannot be empty".into());
+        }
     }
 
+    if let Some(dimension) = config.embedding.dimension {
+        let model_dimension = config.embedding.model_dimension();
+        if dimension != model_dimension {
+            return Err(format!(
+                "Embedding dimension {} does not match model {} which produces {}-dimensional embeddings",
+                dimension,
+                config.embedding.model_name(),
+                model_dimension
+            )
+            .into());
+        }
+    }
+
+    if config.ingest.decode_workers == 0 || config.ingest.batch_size == 0 {
+        return Err("Ingest decode_workers and batch_size must be greater than zero".into());
+    }
+
+    if config.storage.thumbnail_sizes.contains(&0) {
+        return Err("Thumbnail sizes must be greater than zero".into());
+    }
+
+    crate::core::library::validate_layout(&config.storage.library_layout)?;
+
     if !config.storage.media_path.exists() {
         std::fs::create_dir_all(&config.storage.media_path)?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_setting_parsing() {
+        assert_eq!(
+            DeviceSetting::try_from("cpu".to_string()),
+            Ok(DeviceSetting::Cpu)
+        );
+        assert_eq!(
+            DeviceSetting::try_from("AUTO".to_string()),
+            Ok(DeviceSetting::Auto)
+        );
+        assert_eq!(
+            DeviceSetting::try_from("cuda".to_string()),
+            Ok(DeviceSetting::Cuda(0))
+        );
+        assert_eq!(
+            DeviceSetting::try_from("cuda:2".to_string()),
+            Ok(DeviceSetting::Cuda(2))
+        );
+        assert!(DeviceSetting::try_from("cuda:x".to_string()).is_err());
+        assert!(DeviceSetting::try_from("tpu".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_import_mode_parsing() {
+        assert_eq!("copy".parse(), Ok(ImportMode::Copy));
+        assert_eq!("Move".parse(), Ok(ImportMode::Move));
+        assert_eq!("reference".parse(), Ok(ImportMode::Reference));
+        assert!("link".parse::<ImportMode>().is_err());
+    }
+
+    #[test]
+    fn test_model_dimension_follows_backend() {
+        let mut config = EmbeddingConfig::default();
+        assert_eq!(config.model_dimension(), 512);
+
+        config.backend = EmbeddingBackend::Siglip;
+        assert_eq!(config.model_dimension(), 768);
+        assert_eq!(config.model_name(), "siglip-base-patch16-224");
+    }
+}
@@ -27,3 +27,21 @@ pub async fn check_connection(pool: &PgPool) -> Result<()> {
     sqlx::query("SELECT 1").execute(pool).await?;
     Ok(())
 }
+
+/// Dimension of the `embeddings.embedding` vector column, if it has one.
+///
+/// pgvector stores the declared dimension as the column's type modifier.
+pub async fn embedding_column_dimension(pool: &PgPool) -> Result<Option<usize>> {
+    let typmod = sqlx::query_scalar!(
+        r#"
+        SELECT a.atttypmod
+        FROM pg_attribute a
+        WHERE a.attrelid = 'embeddings'::regclass
+          AND a.attname = 'embedding'
+        "#
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(usize::try_from(typmod).ok().filter(|&dim| dim > 0))
+}
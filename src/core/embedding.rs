@@ -1,83 +1,414 @@
+use crate::core::config::{EmbeddingBackend, EmbeddingConfig, LongTextMode};
+#[cfg(test)]
+use crate::core::fake::FakeEmbedder;
+use crate::core::models;
+use crate::core::preprocessing::ImagePreprocessing;
+use crate::core::siglip::SiglipEmbedder;
 use anyhow::{Error as E, Result as AnyhowResult};
 use candle_core::{DType, Device, Tensor};
 use candle_nn::VarBuilder;
 use candle_transformers::models::clip;
 use image::DynamicImage;
+use serde::{Deserialize, Serialize};
 use std::path::Path;
+use std::sync::Arc;
+use thiserror::Error;
 use tokenizers::Tokenizer;
 
+/// Token id used to right-pad batched text inputs.
+const PAD_TOKEN_ID: u32 = 0;
+
+/// A model that embeds images and texts into a shared vector space.
+///
+/// Ingest, search and the API only talk to the model through this trait, so
+/// backends can be swapped in `embedding.backend`.
+pub trait Embedder: Send + Sync {
+    /// Identifier recorded alongside stored embeddings
+    fn model_id(&self) -> &str;
+
+    /// Dimension of the embeddings the model produces
+    fn dimension(&self) -> usize;
+
+    /// Embed a batch of images.
+    ///
+    /// Returns a `(images.len(), dim)` tensor with one L2-normalized row per
+    /// input, in input order.
+    fn encode_images(&self, images: &[DynamicImage]) -> AnyhowResult<Tensor>;
+
+    /// Embed a batch of texts.
+    ///
+    /// Returns a `(texts.len(), dim)` tensor with one L2-normalized row per
+    /// input, in input order, along with how each text was fitted into the
+    /// text encoder's context under `embedding.long_text`. Fails with
+    /// [`TextTooLong`] when a text doesn't fit and the mode is `error`.
+    /// Backends without a context limit always report [`TextFit::Whole`].
+    fn encode_texts(&self, texts: &[&str]) -> AnyhowResult<(Tensor, Vec<TextFit>)>;
+
+    fn encode_image(&self, image: &DynamicImage) -> AnyhowResult<Tensor> {
+        self.encode_images(std::slice::from_ref(image))
+    }
+
+    fn encode_text(&self, text: &str) -> AnyhowResult<(Tensor, TextFit)> {
+        let (embedding, fits) = self.encode_texts(&[text])?;
+        Ok((embedding, fits[0]))
+    }
+
+    fn compute_similarity(&self, image: &DynamicImage, text: &str) -> AnyhowResult<f32> {
+        let image_embedding = self.encode_image(image)?;
+        let (text_embedding, _) = self.encode_text(text)?;
+
+        cosine_similarity(&image_embedding, &text_embedding)
+    }
+}
+
+/// How a text was fitted into the text encoder's context.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TextFit {
+    /// The whole text was embedded
+    Whole,
+    /// Only the first `limit` of `tokens` tokens were embedded
+    Truncated { tokens: usize, limit: usize },
+    /// The text was embedded as the average of `windows` windows
+    Chunked { tokens: usize, windows: usize },
+}
+
+/// A text longer than the text encoder's context, with `embedding.long_text`
+/// set to `error`.
+#[derive(Debug, Error)]
+#[error("Text is {tokens} tokens long but the model reads at most {limit}")]
+pub struct TextTooLong {
+    pub tokens: usize,
+    pub limit: usize,
+}
+
+/// The context of a text encoder and how longer texts are fitted into it.
+///
+/// Tokenizers wrap text in `leading` start tokens and a single end token that
+/// the encoder pools on, so truncated and chunked sequences keep both.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TextWindow {
+    pub leading: usize,
+    /// Maximum sequence length, special tokens included
+    pub limit: usize,
+    pub mode: LongTextMode,
+}
+
+impl TextWindow {
+    /// Content tokens that fit next to the special tokens
+    fn content_len(&self) -> usize {
+        self.limit - self.leading - 1
+    }
+
+    pub fn fit(&self, tokens: usize) -> Result<TextFit, TextTooLong> {
+        if tokens <= self.limit {
+            return Ok(TextFit::Whole);
+        }
+
+        let limit = self.limit;
+        match self.mode {
+            LongTextMode::Truncate => Ok(TextFit::Truncated { tokens, limit }),
+            LongTextMode::Error => Err(TextTooLong { tokens, limit }),
+            LongTextMode::Chunk => Ok(TextFit::Chunked {
+                tokens,
+                windows: (tokens - self.leading - 1).div_ceil(self.content_len()),
+            }),
+        }
+    }
+
+    /// Fit a tokenized text into one or more sequences of at most `limit`
+    /// tokens, returning how it was fitted along with the sequences.
+    pub fn split(&self, mut tokens: Vec<u32>) -> Result<(TextFit, Vec<Vec<u32>>), TextTooLong> {
+        let fit = self.fit(tokens.len())?;
+        let sequences = match fit {
+            TextFit::Whole => vec![tokens],
+            TextFit::Truncated { limit, .. } => {
+                let end = tokens[tokens.len() - 1];
+                tokens.truncate(limit - 1);
+                tokens.push(end);
+                vec![tokens]
+            }
+            TextFit::Chunked { .. } => {
+                let (start, rest) = tokens.split_at(self.leading);
+                let (content, end) = rest.split_at(rest.len() - 1);
+                content
+                    .chunks(self.content_len())
+                    .map(|chunk| [start, chunk, end].concat())
+                    .collect()
+            }
+        };
+        Ok((fit, sequences))
+    }
+}
+
+/// Average the L2-normalized window embeddings of each text.
+///
+/// `windows[i]` consecutive rows of `embeddings` belong to text `i`. Their mean
+/// is normalized again, so every text ends up with one unit-length row.
+pub(crate) fn average_windows(embeddings: &Tensor, windows: &[usize]) -> AnyhowResult<Tensor> {
+    if windows.iter().all(|&count| count == 1) {
+        return Ok(embeddings.clone());
+    }
+
+    let mut start = 0;
+    let mut rows = Vec::with_capacity(windows.len());
+    for &count in windows {
+        rows.push(embeddings.narrow(0, start, count)?.mean_keepdim(0)?);
+        start += count;
+    }
+
+    Ok(clip::div_l2_norm(&Tensor::cat(&rows, 0)?)?)
+}
+
+/// Load the backend selected in the embedding config, fetching its files
+/// from the Hugging Face hub if they aren't cached yet.
+pub fn load_embedder(config: &EmbeddingConfig, device: Device) -> AnyhowResult<Arc<dyn Embedder>> {
+    let embedder: Arc<dyn Embedder> = match config.backend {
+        EmbeddingBackend::Clip => {
+            let files = models::resolve_model_files(config)?;
+            let preprocessing = ImagePreprocessing::load(
+                files.preprocessor.as_deref(),
+                ImagePreprocessing::default(),
+                &config.preprocessing,
+            )?;
+            Arc::new(ClipEmbedder::new(
+                config.model,
+                &files.weights,
+                &files.tokenizer,
+                preprocessing,
+                config.long_text,
+                device,
+            )?)
+        }
+        EmbeddingBackend::Siglip => {
+            let files = models::resolve_model_files(config)?;
+            let preprocessing = ImagePreprocessing::load(
+                files.preprocessor.as_deref(),
+                ImagePreprocessing::siglip(),
+                &config.preprocessing,
+            )?;
+            Arc::new(SiglipEmbedder::new(
+                &files.weights,
+                &files.tokenizer,
+                preprocessing,
+                config.long_text,
+                device,
+            )?)
+        }
+        #[cfg(test)]
+        EmbeddingBackend::Fake => Arc::new(FakeEmbedder::new(config.model_dimension())),
+    };
+
+    Ok(embedder)
+}
+
+/// The supported OpenAI CLIP architectures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+pub enum ClipVariant {
+    #[default]
+    #[serde(rename = "vit-b-32")]
+    VitBasePatch32,
+    #[serde(rename = "vit-b-16")]
+    VitBasePatch16,
+    #[serde(rename = "vit-l-14")]
+    VitLargePatch14,
+}
+
+impl ClipVariant {
+    /// Name recorded alongside stored embeddings
+    pub fn model_name(&self) -> &'static str {
+        match self {
+            ClipVariant::VitBasePatch32 => "clip-vit-base-patch32",
+            ClipVariant::VitBasePatch16 => "clip-vit-base-patch16",
+            ClipVariant::VitLargePatch14 => "clip-vit-large-patch14",
+        }
+    }
+
+    /// Hugging Face hub repository of OpenAI's weights for this architecture
+    pub fn hub_id(&self) -> &'static str {
+        match self {
+            ClipVariant::VitBasePatch32 => "openai/clip-vit-base-patch32",
+            ClipVariant::VitBasePatch16 => "openai/clip-vit-base-patch16",
+            ClipVariant::VitLargePatch14 => "openai/clip-vit-large-patch14",
+        }
+    }
+
+    /// Dimension of the embeddings the model produces
+    pub fn dimension(&self) -> usize {
+        self.clip_config().text_config.projection_dim
+    }
+
+    // The values come from the "text_config" and "vision_config" sections of
+    // each model's config.json on the Hugging Face hub.
+    fn clip_config(&self) -> clip::ClipConfig {
+        match self {
+            ClipVariant::VitBasePatch32 => clip::ClipConfig::vit_base_patch32(),
+            ClipVariant::VitBasePatch16 => {
+                let mut config = clip::ClipConfig::vit_base_patch32();
+                config.vision_config.patch_size = 16;
+                config
+            }
+            ClipVariant::VitLargePatch14 => clip::ClipConfig {
+                text_config: clip::text_model::ClipTextConfig {
+                    embed_dim: 768,
+                    intermediate_size: 3072,
+                    num_attention_heads: 12,
+                    projection_dim: 768,
+                    ..clip::text_model::ClipTextConfig::vit_base_patch32()
+                },
+                vision_config: clip::vision_model::ClipVisionConfig {
+                    embed_dim: 1024,
+                    intermediate_size: 4096,
+                    num_hidden_layers: 24,
+                    num_attention_heads: 16,
+                    projection_dim: 768,
+                    image_size: 224,
+                    patch_size: 14,
+                    ..clip::vision_model::ClipVisionConfig::vit_base_patch32()
+                },
+                logit_scale_init_value: 2.6592,
+                image_size: 224,
+            },
+        }
+    }
+}
+
 pub struct ClipEmbedder {
     model: clip::ClipModel,
     tokenizer: Tokenizer,
     device: Device,
     config: clip::ClipConfig,
+    variant: ClipVariant,
+    preprocessing: ImagePreprocessing,
+    text_window: TextWindow,
 }
 
 impl ClipEmbedder {
-    pub fn new(model_path: &Path, tokenizer_path: &Path, device: Device) -> AnyhowResult<Self> {
-        let config = clip::ClipConfig::vit_base_patch32();
+    pub fn new(
+        variant: ClipVariant,
+        model_path: &Path,
+        tokenizer_path: &Path,
+        preprocessing: ImagePreprocessing,
+        long_text: LongTextMode,
+        device: Device,
+    ) -> AnyhowResult<Self> {
+        let config = variant.clip_config();
+        if preprocessing.crop_size as usize != config.image_size {
+            return Err(E::msg(format!(
+                "Preprocessing crops images to {}px but {} expects {}px input",
+                preprocessing.crop_size,
+                variant.model_name(),
+                config.image_size
+            )));
+        }
+
         let vb = unsafe {
             VarBuilder::from_mmaped_safetensors(&[model_path.to_path_buf()], DType::F32, &device)?
         };
         let model = clip::ClipModel::new(vb, &config)?;
         let tokenizer = Tokenizer::from_file(tokenizer_path).map_err(E::msg)?;
+        // CLIP wraps text in a start and an end of text token
+        let text_window = TextWindow {
+            leading: 1,
+            limit: config.text_config.max_position_embeddings,
+            mode: long_text,
+        };
 
         Ok(Self {
             model,
             tokenizer,
             device,
             config,
+            variant,
+            preprocessing,
+            text_window,
         })
     }
 
-    /// Load an image into a tensor.
-    /// The image is resized to the model's image size and converted to RGB.
+    /// Load an image into a normalized `(3, size, size)` tensor, as described
+    /// by the model's [`ImagePreprocessing`].
     fn load_image_tensor(&self, image: &DynamicImage) -> AnyhowResult<Tensor> {
-        let (height, width) = (self.config.image_size, self.config.image_size);
-        let img = image.resize_to_fill(
-            width as u32,
-            height as u32,
-            image::imageops::FilterType::Triangle,
-        );
-        let img = img.to_rgb8();
-        let img = img.into_raw();
-        let img = Tensor::from_vec(img, (height, width, 3), &self.device)?
-            .permute((2, 0, 1))?
-            .to_dtype(DType::F32)?
-            .affine(2. / 255., -1.)?;
-
-        Ok(img)
+        let size = self.preprocessing.crop_size as usize;
+        let values = self.preprocessing.pixel_values(image);
+        Ok(Tensor::from_vec(values, (3, size, size), &self.device)?)
     }
 
-    fn tokenize_sequence(&self, sequence: &str) -> AnyhowResult<Tensor> {
+    fn tokenize_sequence(&self, sequence: &str) -> AnyhowResult<Vec<u32>> {
         let encoding = self.tokenizer.encode(sequence, true).map_err(E::msg)?;
-        let tokens = encoding.get_ids().to_vec();
+        Ok(encoding.get_ids().to_vec())
+    }
+
+    /// Pad a batch of token sequences into a single `(batch, seq_len)`
+    /// tensor.
+    ///
+    /// Shorter sequences are right-padded to the longest one. CLIP's text
+    /// encoder is causally masked and pools the hidden state at the end of
+    /// text token (the highest token id), so padding placed after it never
+    /// influences the pooled features and no extra attention mask is needed.
+    /// Padding uses id 0, which keeps the end of text token the unique argmax.
+    fn pad_batch(&self, token_ids: Vec<Vec<u32>>) -> AnyhowResult<Tensor> {
+        let max_len = token_ids.iter().map(Vec::len).max().unwrap_or(0);
+
+        let rows = token_ids
+            .into_iter()
+            .map(|mut tokens| {
+                tokens.resize(max_len, PAD_TOKEN_ID);
+                Tensor::new(tokens, &self.device)
+            })
+            .collect::<candle_core::Result<Vec<_>>>()?;
 
-        let input_ids = Tensor::new(tokens, &self.device)?.unsqueeze(0)?;
-        Ok(input_ids)
+        Ok(Tensor::stack(&rows, 0)?)
     }
+}
 
-    pub fn encode_image(&self, image: &DynamicImage) -> AnyhowResult<Tensor> {
-        let tensor = self.load_image_tensor(image)?;
-        // Create a batch dimension
-        let tensor = tensor.unsqueeze(0)?;
-        let embedding = self.model.get_image_features(&tensor)?;
-        let embedding_normalized = clip::div_l2_norm(&embedding)?;
-        Ok(embedding_normalized)
+impl Embedder for ClipEmbedder {
+    fn model_id(&self) -> &str {
+        self.variant.model_name()
     }
 
-    pub fn encode_text(&self, text: &str) -> AnyhowResult<Tensor> {
-        let input_ids = self.tokenize_sequence(text)?;
-        let embedding = self.model.get_text_features(&input_ids)?;
+    fn dimension(&self) -> usize {
+        self.variant.dimension()
+    }
+
+    /// Embed a batch of images in a single forward pass.
+    fn encode_images(&self, images: &[DynamicImage]) -> AnyhowResult<Tensor> {
+        if images.is_empty() {
+            return Err(E::msg("Cannot encode an empty batch of images"));
+        }
+
+        let tensors = images
+            .iter()
+            .map(|image| self.load_image_tensor(image))
+            .collect::<AnyhowResult<Vec<_>>>()?;
+        let batch = Tensor::stack(&tensors, 0)?;
+        let embedding = self.model.get_image_features(&batch)?;
         let embedding_normalized = clip::div_l2_norm(&embedding)?;
         Ok(embedding_normalized)
     }
 
-    pub fn compute_similarity(&self, image: &DynamicImage, text: &str) -> AnyhowResult<f32> {
-        let image_embedding = self.encode_image(image)?;
-        let text_embedding = self.encode_text(text)?;
+    /// Embed a batch of texts in a single forward pass.
+    ///
+    /// Texts longer than the context are truncated, rejected or embedded as
+    /// the average of their windows, depending on `embedding.long_text`.
+    fn encode_texts(&self, texts: &[&str]) -> AnyhowResult<(Tensor, Vec<TextFit>)> {
+        if texts.is_empty() {
+            return Err(E::msg("Cannot encode an empty batch of texts"));
+        }
 
-        cosine_similarity(&image_embedding, &text_embedding)
+        let mut sequences = Vec::with_capacity(texts.len());
+        let mut windows = Vec::with_capacity(texts.len());
+        let mut fits = Vec::with_capacity(texts.len());
+        for text in texts {
+            let (fit, split) = self.text_window.split(self.tokenize_sequence(text)?)?;
+            fits.push(fit);
+            windows.push(split.len());
+            sequences.extend(split);
+        }
+
+        let input_ids = self.pad_batch(sequences)?;
+        let embedding = self.model.get_text_features(&input_ids)?;
+        let embedding_normalized = clip::div_l2_norm(&embedding)?;
+        Ok((average_windows(&embedding_normalized, &windows)?, fits))
     }
 }
 
@@ -93,8 +424,140 @@ fn cosine_similarity(emb1: &Tensor, emb2: &Tensor) -> AnyhowResult<f32> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use anyhow::Context;
     use approx::assert_relative_eq;
     use candle_core::Tensor;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_clip_variant_dimensions() {
+        assert_eq!(ClipVariant::VitBasePatch32.dimension(), 512);
+        assert_eq!(ClipVariant::VitBasePatch16.dimension(), 512);
+        assert_eq!(ClipVariant::VitLargePatch14.dimension(), 768);
+    }
+
+    #[test]
+    fn test_text_window_keeps_special_tokens() {
+        // Start token 100, content 1..=7, end token 200
+        let tokens: Vec<u32> = std::iter::once(100)
+            .chain(1..=7)
+            .chain(std::iter::once(200))
+            .collect();
+        let window = |mode| TextWindow {
+            leading: 1,
+            limit: 5,
+            mode,
+        };
+
+        let truncate = window(LongTextMode::Truncate);
+        assert_eq!(
+            truncate.fit(tokens.len()).unwrap(),
+            TextFit::Truncated {
+                tokens: 9,
+                limit: 5
+            }
+        );
+        assert_eq!(
+            truncate.split(tokens.clone()).unwrap().1,
+            vec![vec![100, 1, 2, 3, 200]]
+        );
+        assert_eq!(
+            truncate.split(vec![100, 1, 200]).unwrap(),
+            (TextFit::Whole, vec![vec![100, 1, 200]])
+        );
+
+        assert!(window(LongTextMode::Error).split(tokens.clone()).is_err());
+
+        let chunk = window(LongTextMode::Chunk);
+        assert_eq!(
+            chunk.fit(tokens.len()).unwrap(),
+            TextFit::Chunked {
+                tokens: 9,
+                windows: 3
+            }
+        );
+        assert_eq!(
+            chunk.split(tokens).unwrap().1,
+            vec![
+                vec![100, 1, 2, 3, 200],
+                vec![100, 4, 5, 6, 200],
+                vec![100, 7, 200],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_average_windows_renormalizes() -> AnyhowResult<()> {
+        let embeddings = Tensor::new(&[[1f32, 0.], [0., 1.], [0., 1.]], &Device::Cpu)?;
+
+        let rows = average_windows(&embeddings, &[2, 1])?.to_vec2::<f32>()?;
+        assert_eq!(rows.len(), 2);
+        assert_relative_eq!(rows[0][0], std::f32::consts::FRAC_1_SQRT_2, epsilon = 1e-6);
+        assert_relative_eq!(rows[0][1], std::f32::consts::FRAC_1_SQRT_2, epsilon = 1e-6);
+        assert_eq!(rows[1], vec![0., 1.]);
+        Ok(())
+    }
+
+    /// A 64x48 gradient, generated identically by `scripts/clip_reference.py`
+    fn fixture_image() -> DynamicImage {
+        DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 48, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 5) as u8, ((x + y) * 2) as u8])
+        }))
+    }
+
+    fn dot(a: &[f32], b: &[f32]) -> f32 {
+        a.iter().zip(b).map(|(a, b)| a * b).sum()
+    }
+
+    #[test]
+    #[ignore = "downloads openai/clip-vit-base-patch32; run scripts/clip_reference.py first"]
+    fn test_clip_matches_reference_embeddings() -> AnyhowResult<()> {
+        #[derive(Deserialize)]
+        struct Reference {
+            image: Vec<f32>,
+            texts: HashMap<String, Vec<f32>>,
+        }
+
+        let path =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/clip-vit-base-patch32.json");
+        // The fixture needs torch and transformers to generate, so it is
+        // written by the script rather than by the test
+        let json = std::fs::read_to_string(&path).with_context(|| {
+            format!(
+                "{:?} is missing; generate it with scripts/clip_reference.py",
+                path
+            )
+        })?;
+        let reference: Reference = serde_json::from_str(&json)?;
+        let embedder = load_embedder(&EmbeddingConfig::default(), Device::Cpu)?;
+
+        let image = embedder
+            .encode_image(&fixture_image())?
+            .flatten_all()?
+            .to_vec1::<f32>()?;
+        let similarity = dot(&image, &reference.image);
+        assert!(
+            similarity > 0.99,
+            "image embedding drifted from transformers: cosine similarity {}",
+            similarity
+        );
+
+        for (text, expected) in &reference.texts {
+            let actual = embedder
+                .encode_text(text)?
+                .0
+                .flatten_all()?
+                .to_vec1::<f32>()?;
+            let similarity = dot(&actual, expected);
+            assert!(
+                similarity > 0.999,
+                "text embedding of {:?} drifted from transformers: cosine similarity {}",
+                text,
+                similarity
+            );
+        }
+        Ok(())
+    }
 
     #[test]
     fn test_cosine_similarity_identical_vectors() -> AnyhowResult<()> {
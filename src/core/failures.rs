@@ -0,0 +1,128 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::postgres::PgPool;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// The pipeline stage a file failed in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureStage {
+    /// Reading, sniffing or decoding the file
+    Decode,
+    /// Running the model or generating derived images
+    Embed,
+    /// Copying the file into the library
+    Import,
+    /// Any database read or write
+    Db,
+}
+
+impl FailureStage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailureStage::Decode => "decode",
+            FailureStage::Embed => "embed",
+            FailureStage::Import => "import",
+            FailureStage::Db => "db",
+        }
+    }
+}
+
+impl fmt::Display for FailureStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A recorded ingest failure.
+#[derive(Debug, Clone, Serialize)]
+pub struct IngestFailure {
+    pub file_path: String,
+    pub stage: String,
+    pub error: String,
+    pub attempts: i32,
+    pub failed_at: Option<DateTime<Utc>>,
+}
+
+/// The path a failure is recorded under, so it matches however the file is
+/// reached later: canonical while the file exists, otherwise absolute.
+pub fn failure_path(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path)
+        .or_else(|_| std::path::absolute(path))
+        .unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Record the outcome of ingested files: failed files are stored (bumping
+/// their attempt count if they failed before) and files that succeeded are
+/// cleared.
+pub async fn record_results(
+    pool: &PgPool,
+    failed: &[(PathBuf, FailureStage, String)],
+    succeeded: &[PathBuf],
+) -> Result<()> {
+    let mut tx = pool.begin().await?;
+
+    for (path, stage, error) in failed {
+        sqlx::query!(
+            r#"
+            INSERT INTO ingest_failures (file_path, stage, error)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (file_path) DO UPDATE
+            SET stage = EXCLUDED.stage,
+                error = EXCLUDED.error,
+                attempts = ingest_failures.attempts + 1,
+                failed_at = CURRENT_TIMESTAMP
+            "#,
+            failure_path(path).to_string_lossy().to_string(),
+            stage.as_str(),
+            error
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    let succeeded: Vec<String> = succeeded
+        .iter()
+        .map(|path| failure_path(path).to_string_lossy().to_string())
+        .collect();
+    clear(&mut tx, &succeeded).await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Forget failures for the given paths.
+pub async fn clear_failures(pool: &PgPool, paths: &[String]) -> Result<()> {
+    let mut conn = pool.acquire().await?;
+    clear(&mut conn, paths).await
+}
+
+async fn clear(conn: &mut sqlx::PgConnection, paths: &[String]) -> Result<()> {
+    if !paths.is_empty() {
+        sqlx::query!(
+            "DELETE FROM ingest_failures WHERE file_path = ANY($1)",
+            paths
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Every recorded failure, oldest first.
+pub async fn list_failures(pool: &PgPool) -> Result<Vec<IngestFailure>> {
+    let failures = sqlx::query_as!(
+        IngestFailure,
+        r#"
+        SELECT file_path, stage, error, attempts, failed_at
+        FROM ingest_failures
+        ORDER BY failed_at, file_path
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(failures)
+}
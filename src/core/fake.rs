@@ -0,0 +1,136 @@
+//! Hash-based embeddings for tests, selected with the `fake` backend.
+
+use crate::core::embedding::{Embedder, TextFit};
+use anyhow::{Error as E, Result as AnyhowResult};
+use candle_core::{Device, Tensor};
+use candle_transformers::models::clip;
+use image::DynamicImage;
+use sha2::{Digest, Sha256};
+
+/// Name [`FakeEmbedder`] records alongside its embeddings
+pub const MODEL_ID: &str = "fake";
+
+/// Dimension of [`FakeEmbedder`] embeddings unless `embedding.dimension` is set
+pub const DEFAULT_DIMENSION: usize = 512;
+
+/// A deterministic stand-in for a real model, so tests run without
+/// downloading weights.
+///
+/// Every input is hashed and the digest stretched into a unit vector, so the
+/// same pixels or text always embed identically while different inputs land
+/// close to orthogonal. The vectors carry no meaning beyond that.
+pub struct FakeEmbedder {
+    dimension: usize,
+}
+
+impl FakeEmbedder {
+    pub fn new(dimension: usize) -> Self {
+        Self { dimension }
+    }
+
+    fn hash_embedding(&self, kind: &str, bytes: &[u8]) -> Vec<f32> {
+        let seed = Sha256::new()
+            .chain_update(kind)
+            .chain_update(bytes)
+            .finalize();
+
+        let mut values = Vec::with_capacity(self.dimension);
+        let mut block_index = 0u32;
+        while values.len() < self.dimension {
+            let block = Sha256::new()
+                .chain_update(seed)
+                .chain_update(block_index.to_le_bytes())
+                .finalize();
+            values.extend(block.chunks_exact(4).map(|chunk| {
+                let value = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+                value as f32 / u32::MAX as f32 * 2. - 1.
+            }));
+            block_index += 1;
+        }
+        values.truncate(self.dimension);
+        values
+    }
+
+    fn to_tensor(&self, rows: Vec<Vec<f32>>) -> AnyhowResult<Tensor> {
+        let count = rows.len();
+        let values: Vec<f32> = rows.into_iter().flatten().collect();
+        let embedding = Tensor::from_vec(values, (count, self.dimension), &Device::Cpu)?;
+        Ok(clip::div_l2_norm(&embedding)?)
+    }
+}
+
+impl Embedder for FakeEmbedder {
+    fn model_id(&self) -> &str {
+        MODEL_ID
+    }
+
+    fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    fn encode_images(&self, images: &[DynamicImage]) -> AnyhowResult<Tensor> {
+        if images.is_empty() {
+            return Err(E::msg("Cannot encode an empty batch of images"));
+        }
+
+        let rows = images
+            .iter()
+            .map(|image| {
+                let pixels = image.to_rgb8();
+                let mut bytes = Vec::with_capacity(pixels.len() + 8);
+                bytes.extend(pixels.width().to_le_bytes());
+                bytes.extend(pixels.height().to_le_bytes());
+                bytes.extend(pixels.into_raw());
+                self.hash_embedding("image", &bytes)
+            })
+            .collect();
+        self.to_tensor(rows)
+    }
+
+    fn encode_texts(&self, texts: &[&str]) -> AnyhowResult<(Tensor, Vec<TextFit>)> {
+        if texts.is_empty() {
+            return Err(E::msg("Cannot encode an empty batch of texts"));
+        }
+
+        let rows = texts
+            .iter()
+            .map(|text| self.hash_embedding("text", text.as_bytes()))
+            .collect();
+        Ok((self.to_tensor(rows)?, vec![TextFit::Whole; texts.len()]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn test_fake_embedder_is_deterministic() -> AnyhowResult<()> {
+        let embedder = FakeEmbedder::new(64);
+        let red =
+            DynamicImage::ImageRgb8(image::RgbImage::from_pixel(4, 4, image::Rgb([255, 0, 0])));
+        let blue =
+            DynamicImage::ImageRgb8(image::RgbImage::from_pixel(4, 4, image::Rgb([0, 0, 255])));
+
+        let embeddings = embedder.encode_images(&[red.clone(), blue, red])?;
+        assert_eq!(embeddings.dims(), &[3, 64]);
+
+        let rows = embeddings.to_vec2::<f32>()?;
+        assert_eq!(rows[0], rows[2]);
+        assert_ne!(rows[0], rows[1]);
+
+        let norm: f32 = rows[1]
+            .iter()
+            .map(|value| value * value)
+            .sum::<f32>()
+            .sqrt();
+        assert_relative_eq!(norm, 1.0, epsilon = 1e-5);
+
+        let (text, fits) = embedder.encode_texts(&["a red square", "a red square"])?;
+        let text = text.to_vec2::<f32>()?;
+        assert_eq!(text[0], text[1]);
+        assert_eq!(fits, vec![TextFit::Whole; 2]);
+        Ok(())
+    }
+}
@@ -1,44 +1,524 @@
 use crate::core::state::AppState;
 use anyhow::Result;
 use sqlx;
-use tracing::info;
+use sqlx::postgres::{PgConnection, PgPool};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{debug, info, warn};
 use uuid::Uuid;
 
-use super::media::MediaDetails;
+use super::config::{ImportMode, StorageConfig};
+use super::library::import_original;
+use super::media::{hash_bytes, MediaDetails};
+use super::thumbnails::{generate_thumbnails, save_thumbnails, Thumbnail};
 
-pub async fn process_image(media_details: MediaDetails, state: &AppState) -> Result<()> {
-    // Generate the embedding
-    let embedding = state.embedder.encode_image(&media_details.image)?;
-    info!("Generated embedding with shape: {:?}", embedding.shape());
+/// What happened to a file passed to [`process_image`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IngestOutcome {
+    /// The file was embedded and stored as a new media item
+    New(Uuid),
+    /// The content was already known under a path that no longer exists, so
+    /// the existing media item was pointed at the new location
+    Moved(Uuid),
+    /// The content is already in the library at a path that still exists
+    Skipped(Uuid),
+}
 
-    // Convert embedding to a format suitable for database storage
-    let embedding_vec = embedding.flatten_all()?.to_vec1::<f32>()?;
+impl IngestOutcome {
+    pub fn media_id(&self) -> Uuid {
+        match self {
+            IngestOutcome::New(id) | IngestOutcome::Moved(id) | IngestOutcome::Skipped(id) => *id,
+        }
+    }
+}
 
-    // Generate UUIDs for the database records
-    let media_id = Uuid::new_v4();
-    let embedding_id = Uuid::new_v4();
+/// A media row that already holds some content hash.
+#[derive(Debug, Clone)]
+pub struct ExistingMedia {
+    pub id: Uuid,
+    pub file_path: String,
+    /// Whether the current model has embedded it
+    pub embedded: bool,
+}
 
-    // Save the media information to the database
-    let mut tx = state.db_pool.begin().await?;
+/// A decoded image ready to be written, along with its embedding.
+///
+/// The decoded pixels are dropped once the embedding and thumbnails are
+/// made, so only the image size is kept.
+pub struct EmbeddedMedia {
+    pub media_id: Uuid,
+    pub details: MediaDetails,
+    pub width: u32,
+    pub height: u32,
+    pub embedding: Vec<f32>,
+    pub thumbnails: Vec<Thumbnail>,
+    /// Where the file was imported from, if it was copied into the library
+    pub imported_from: Option<PathBuf>,
+    /// How the file was resolved if its content is already stored and only
+    /// lacks an embedding from the current model
+    pub stored_as: Option<IngestOutcome>,
+}
+
+impl EmbeddedMedia {
+    pub fn new(
+        media_id: Uuid,
+        mut details: MediaDetails,
+        embedding: Vec<f32>,
+        thumbnails: Vec<Thumbnail>,
+    ) -> Self {
+        let image = std::mem::take(&mut details.image);
+        Self {
+            media_id,
+            width: image.width(),
+            height: image.height(),
+            details,
+            embedding,
+            thumbnails,
+            imported_from: None,
+            stored_as: None,
+        }
+    }
+
+    /// What ingesting the file amounted to once the batch is written
+    pub fn outcome(&self) -> IngestOutcome {
+        self.stored_as.unwrap_or(IngestOutcome::New(self.media_id))
+    }
+
+    /// The path the file was ingested from, before any import
+    pub fn source_path(&self) -> PathBuf {
+        self.imported_from
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(&self.details.file_path))
+    }
+}
+
+/// Copy a file with new content into the library, unless it is referenced in
+/// place. Returns the original path if the file was imported.
+pub fn prepare_import(
+    details: &mut MediaDetails,
+    mode: ImportMode,
+    storage: &StorageConfig,
+) -> Result<Option<PathBuf>> {
+    match mode {
+        ImportMode::Reference => Ok(None),
+        ImportMode::Copy | ImportMode::Move => import_original(details, storage).map(Some),
+    }
+}
+
+/// Clean up after imported files once their batch has been written or has
+/// failed to be.
+///
+/// Moved originals are deleted only after they are safely stored, and
+/// library copies of a failed batch are removed so they aren't orphaned.
+pub fn finish_import(batch: &[EmbeddedMedia], mode: ImportMode, stored: bool) {
+    for item in batch {
+        let Some(source) = &item.imported_from else {
+            continue;
+        };
+
+        let leftover = if stored {
+            (mode == ImportMode::Move).then_some(source.as_path())
+        } else {
+            Some(Path::new(&item.details.file_path))
+        };
+
+        if let Some(path) = leftover {
+            if let Err(e) = std::fs::remove_file(path) {
+                warn!("Failed to remove {:?}: {}", path, e);
+            }
+        }
+    }
+}
+
+/// Generate thumbnails for newly ingested media.
+///
+/// A failure here shouldn't lose the ingest, so it is logged and the
+/// thumbnails are left for `thumbnails regenerate` to rebuild.
+pub fn thumbnails_for(details: &MediaDetails, storage: &StorageConfig) -> Vec<Thumbnail> {
+    generate_thumbnails(&details.image, &details.content_hash, storage).unwrap_or_else(|e| {
+        warn!(
+            "Failed to generate thumbnails for {}: {}",
+            details.file_path, e
+        );
+        Vec::new()
+    })
+}
+
+/// Embed an image and persist it alongside its embedding.
+///
+/// Files are deduplicated by content hash: content that is already stored is
+/// not re-embedded, and only its path is updated if the original has moved.
+/// Stored content that the current model hasn't embedded yet, such as after
+/// switching models, gets an embedding from it. New content is copied or
+/// moved into the library according to `import_mode`.
+pub async fn process_image(
+    media_details: MediaDetails,
+    import_mode: ImportMode,
+    state: &AppState,
+) -> Result<IngestOutcome> {
+    let model_name = state.embedder.model_id();
+    let existing = find_existing_by_hash(
+        &state.db_pool,
+        std::slice::from_ref(&media_details.content_hash),
+        model_name,
+    )
+    .await?;
+
+    let (media_id, stored_as) = match existing.get(&media_details.content_hash) {
+        Some(existing) => {
+            let outcome = resolve_duplicate(&state.db_pool, existing, &media_details).await?;
+            if existing.embedded {
+                return Ok(outcome);
+            }
+            (existing.id, Some(outcome))
+        }
+        None => (Uuid::new_v4(), None),
+    };
+
+    // Embedding, thumbnailing and importing are CPU and disk bound, so keep
+    // them off the async runtime
+    let embedder = Arc::clone(&state.embedder);
+    let storage = state.config.storage.clone();
+    let item = tokio::task::spawn_blocking(move || -> Result<_> {
+        let embedding = embedder.encode_image(&media_details.image)?;
+        info!("Generated embedding with shape: {:?}", embedding.shape());
+
+        // Convert embedding to a format suitable for database storage
+        let embedding = embedding.flatten_all()?.to_vec1::<f32>()?;
+
+        // Stored content already has its thumbnails and its place in the
+        // library
+        if stored_as.is_some() {
+            let mut item = EmbeddedMedia::new(media_id, media_details, embedding, Vec::new());
+            item.stored_as = stored_as;
+            return Ok(item);
+        }
+
+        let thumbnails = thumbnails_for(&media_details, &storage);
+        let mut item = EmbeddedMedia::new(media_id, media_details, embedding, thumbnails);
+        item.imported_from = prepare_import(&mut item.details, import_mode, &storage)?;
+        Ok(item)
+    })
+    .await??;
+
+    let outcome = item.outcome();
+    let batch = [item];
+    let stored = insert_media_batch(&state.db_pool, model_name, &batch).await;
+    finish_import(&batch, import_mode, stored.is_ok());
+    stored?;
+
+    Ok(outcome)
+}
+
+/// Look up which of the given content hashes are already stored, and whether
+/// `model_name` has embedded them.
+pub async fn find_existing_by_hash(
+    pool: &PgPool,
+    hashes: &[String],
+    model_name: &str,
+) -> Result<HashMap<String, ExistingMedia>> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT m.id, m.file_path, m.content_hash as "content_hash!",
+               EXISTS (
+                   SELECT 1 FROM embeddings e
+                   WHERE e.media_id = m.id AND e.model_name = $2
+               ) as "embedded!"
+        FROM media m
+        WHERE m.content_hash = ANY($1)
+        "#,
+        hashes,
+        model_name
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            (
+                row.content_hash,
+                ExistingMedia {
+                    id: row.id,
+                    file_path: row.file_path,
+                    embedded: row.embedded,
+                },
+            )
+        })
+        .collect())
+}
+
+/// Hash the files of media stored before content hashes were recorded, so
+/// re-ingesting them is recognised as a duplicate.
+///
+/// Copies of content that is already hashed can't take the hash, which is
+/// unique, so they are marked as duplicates of the item holding it and not
+/// hashed again. Files that can't be read keep a NULL hash until `rescan`
+/// finds them. Returns the number of media hashed.
+pub async fn backfill_content_hashes(pool: &PgPool) -> Result<usize> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT id, file_path
+        FROM media
+        WHERE content_hash IS NULL AND duplicate_of IS NULL AND missing_at IS NULL
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+
+    if rows.is_empty() {
+        return Ok(0);
+    }
+    info!("Hashing {} media stored without a content hash", rows.len());
+
+    let mut hashed = 0;
+    let mut duplicates = 0;
+    let mut unreadable = 0;
+    for row in rows {
+        let path = PathBuf::from(&row.file_path);
+        let content_hash = tokio::task::spawn_blocking(move || {
+            std::fs::read(path).map(|bytes| hash_bytes(&bytes))
+        })
+        .await?;
+        let content_hash = match content_hash {
+            Ok(content_hash) => content_hash,
+            Err(e) => {
+                debug!("Cannot hash {}: {}", row.file_path, e);
+                unreadable += 1;
+                continue;
+            }
+        };
+
+        let updated = sqlx::query!(
+            r#"
+            UPDATE media
+            SET content_hash = $2
+            WHERE id = $1
+              AND NOT EXISTS (SELECT 1 FROM media WHERE content_hash = $2)
+            "#,
+            row.id,
+            content_hash
+        )
+        .execute(pool)
+        .await?
+        .rows_affected();
+
+        if updated > 0 {
+            hashed += 1;
+            continue;
+        }
+
+        let original = sqlx::query_scalar!(
+            r#"
+            UPDATE media
+            SET duplicate_of = (SELECT id FROM media WHERE content_hash = $2)
+            WHERE id = $1
+            RETURNING duplicate_of
+            "#,
+            row.id,
+            content_hash
+        )
+        .fetch_one(pool)
+        .await?;
+        duplicates += 1;
+        info!(
+            "{} ({}) duplicates {}",
+            row.file_path,
+            row.id,
+            original.map_or_else(
+                || "content already in the library".to_string(),
+                |id| id.to_string()
+            )
+        );
+    }
+
+    info!("Hashed {} media", hashed);
+    if duplicates > 0 {
+        warn!(
+            "{} media duplicate content already in the library and were marked as copies",
+            duplicates
+        );
+    }
+    if unreadable > 0 {
+        warn!(
+            "{} media files could not be read; run `rescan` to find them",
+            unreadable
+        );
+    }
+    Ok(hashed)
+}
+
+/// Decide what to do with a file whose content is already stored.
+///
+/// If the stored path still exists the file is a copy and is skipped,
+/// otherwise the original was moved and the stored path is updated.
+pub async fn resolve_duplicate(
+    pool: &PgPool,
+    existing: &ExistingMedia,
+    media_details: &MediaDetails,
+) -> Result<IngestOutcome> {
+    if existing.file_path == media_details.file_path || Path::new(&existing.file_path).exists() {
+        info!(
+            "Skipping {} (already ingested as {})",
+            media_details.file_path, existing.id
+        );
+        if existing.file_path == media_details.file_path {
+            // The file may have been flagged missing while it was away
+            sqlx::query!(
+                "UPDATE media SET missing_at = NULL WHERE id = $1 AND missing_at IS NOT NULL",
+                existing.id
+            )
+            .execute(pool)
+            .await?;
+        }
+        return Ok(IngestOutcome::Skipped(existing.id));
+    }
 
     sqlx::query!(
         r#"
-        INSERT INTO media (id, filename, content_type, file_path, file_size, width, height, metadata)
-        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        UPDATE media
+        SET file_path = $1, filename = $2, file_mtime = $3, missing_at = NULL
+        WHERE id = $4
         "#,
-        media_id,
-        media_details.filename,
-        "image/jpeg",
         media_details.file_path,
-        media_details.file_size as i64,
-        media_details.image.width() as i32,
-        media_details.image.height() as i32,
-        serde_json::Value::Null
+        media_details.filename,
+        media_details.modified_at,
+        existing.id
     )
-    .execute(&mut *tx)
+    .execute(pool)
     .await?;
 
-    // Insert the embedding into the database
+    info!(
+        "Updated path of media {} from {} to {}",
+        existing.id, existing.file_path, media_details.file_path
+    );
+    Ok(IngestOutcome::Moved(existing.id))
+}
+
+/// Flag media stored at `path`, or anywhere under it if it is a directory,
+/// as missing from disk. Media with the id `except` is left alone.
+///
+/// Returns the number of media items newly flagged.
+pub async fn mark_missing(pool: &PgPool, path: &str, except: Option<Uuid>) -> Result<u64> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE media
+        SET missing_at = CURRENT_TIMESTAMP
+        WHERE (file_path = $1 OR starts_with(file_path, $1 || '/'))
+          AND ($2::uuid IS NULL OR id <> $2)
+          AND missing_at IS NULL
+        "#,
+        path,
+        except
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Point media stored at `from`, or anywhere under it if it is a directory,
+/// at the same location under `to`.
+///
+/// Returns the number of media items updated.
+pub async fn update_media_paths(pool: &PgPool, from: &str, to: &str) -> Result<u64> {
+    let filename = Path::new(to)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let result = sqlx::query!(
+        r#"
+        UPDATE media
+        SET file_path = $2 || substr(file_path, length($1) + 1),
+            filename = CASE WHEN file_path = $1 THEN $3 ELSE filename END,
+            missing_at = NULL
+        WHERE file_path = $1 OR starts_with(file_path, $1 || '/')
+        "#,
+        from,
+        to,
+        filename
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Insert a batch of media rows, their embeddings and thumbnails in a single
+/// transaction. Items whose content is already stored only get the
+/// embedding.
+pub async fn insert_media_batch(
+    pool: &PgPool,
+    model_name: &str,
+    batch: &[EmbeddedMedia],
+) -> Result<()> {
+    let mut tx = pool.begin().await?;
+
+    for item in batch {
+        let details = &item.details;
+
+        if item.stored_as.is_some() {
+            insert_embedding(&mut tx, item.media_id, model_name, &item.embedding).await?;
+            info!("Added {} embedding to media {}", model_name, item.media_id);
+            continue;
+        }
+
+        let exif = details.exif.as_ref();
+        let metadata = serde_json::to_value(exif)?;
+        let captured_at = exif.and_then(|exif| exif.captured_at);
+        let gps = exif.and_then(|exif| exif.gps);
+
+        sqlx::query!(
+            r#"
+            INSERT INTO media (
+                id, filename, content_type, file_path, file_size, width, height, metadata,
+                content_hash, captured_at, latitude, longitude, file_mtime
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+            "#,
+            item.media_id,
+            details.filename,
+            details.content_type,
+            details.file_path,
+            details.file_size as i64,
+            item.width as i32,
+            item.height as i32,
+            metadata,
+            details.content_hash,
+            captured_at,
+            gps.map(|gps| gps.latitude),
+            gps.map(|gps| gps.longitude),
+            details.modified_at
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let embedding_id =
+            insert_embedding(&mut tx, item.media_id, model_name, &item.embedding).await?;
+
+        save_thumbnails(&mut tx, item.media_id, &item.thumbnails).await?;
+
+        info!(
+            "Saved media with ID: {} and embedding with ID: {}",
+            item.media_id, embedding_id
+        );
+    }
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Store `model_name`'s embedding of a media item. Returns the embedding id.
+async fn insert_embedding(
+    conn: &mut PgConnection,
+    media_id: Uuid,
+    model_name: &str,
+    embedding: &[f32],
+) -> Result<Uuid> {
+    let embedding_id = Uuid::new_v4();
     sqlx::query!(
         r#"
         INSERT INTO embeddings (id, media_id, model_name, model_version, embedding)
@@ -46,19 +526,93 @@ pub async fn process_image(media_details: MediaDetails, state: &AppState) -> Res
         "#,
         embedding_id,
         media_id,
-        "clip-vit-base-patch32", // TODO: get from config
-        "v1",                    // TODO: get from config
-        &embedding_vec as &[f32]
+        model_name,
+        "v1", // TODO: get from config
+        embedding as &[f32]
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(embedding_id)
+}
+
+/// Replace the stored content of an existing media item after its file
+/// changed on disk, keeping its id and tags.
+///
+/// The image is re-embedded and its details, embedding and thumbnails are
+/// overwritten in a single transaction.
+pub async fn reembed_media(
+    media_id: Uuid,
+    media_details: MediaDetails,
+    state: &AppState,
+) -> Result<()> {
+    // Inference and thumbnailing would stall the runtime, as in process_image
+    let embedder = Arc::clone(&state.embedder);
+    let storage = state.config.storage.clone();
+    let item = tokio::task::spawn_blocking(move || -> Result<_> {
+        let embedding = embedder
+            .encode_image(&media_details.image)?
+            .flatten_all()?
+            .to_vec1::<f32>()?;
+        let thumbnails = thumbnails_for(&media_details, &storage);
+        Ok(EmbeddedMedia::new(
+            media_id,
+            media_details,
+            embedding,
+            thumbnails,
+        ))
+    })
+    .await??;
+    let media_details = &item.details;
+
+    let exif = media_details.exif.as_ref();
+    let metadata = serde_json::to_value(exif)?;
+    let captured_at = exif.and_then(|exif| exif.captured_at);
+    let gps = exif.and_then(|exif| exif.gps);
+    let model_name = state.embedder.model_id();
+
+    let mut tx = state.db_pool.begin().await?;
+
+    sqlx::query!(
+        r#"
+        UPDATE media
+        SET content_type = $2, file_size = $3, width = $4, height = $5, metadata = $6,
+            content_hash = $7, captured_at = $8, latitude = $9, longitude = $10,
+            file_mtime = $11, missing_at = NULL
+        WHERE id = $1
+        "#,
+        media_id,
+        media_details.content_type,
+        media_details.file_size as i64,
+        item.width as i32,
+        item.height as i32,
+        metadata,
+        media_details.content_hash,
+        captured_at,
+        gps.map(|gps| gps.latitude),
+        gps.map(|gps| gps.longitude),
+        media_details.modified_at
     )
     .execute(&mut *tx)
     .await?;
 
-    tx.commit().await?;
+    // Embeddings from other models describe the old content too, so they are
+    // dropped rather than left to be found after switching back
+    sqlx::query!("DELETE FROM embeddings WHERE media_id = $1", media_id)
+        .execute(&mut *tx)
+        .await?;
 
-    info!(
-        "Saved media with ID: {} and embedding with ID: {}",
-        media_id, embedding_id
-    );
+    insert_embedding(&mut tx, media_id, model_name, &item.embedding).await?;
+
+    // Thumbnails of the old content are keyed by its hash, so drop the rows
+    // rather than leaving them pointing at the wrong image
+    sqlx::query!("DELETE FROM thumbnails WHERE media_id = $1", media_id)
+        .execute(&mut *tx)
+        .await?;
+    save_thumbnails(&mut tx, media_id, &item.thumbnails).await?;
+
+    tx.commit().await?;
 
+    info!("Re-embedded media {}", media_id);
     Ok(())
 }
@@ -0,0 +1,462 @@
+use crate::core::config::IngestConfig;
+use crate::core::ingest::IngestOutcome;
+use crate::core::pipeline::{self, FileResult, IngestSummary};
+use crate::core::state::AppState;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::postgres::PgPool;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// How often a running job refreshes its lease and checks whether it was
+/// cancelled elsewhere
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long a running job's lease lasts without being refreshed, after which
+/// its runner is presumed dead and another process may take the job over
+const LEASE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Number of per-file results written to the database at once
+const RECORD_BATCH_SIZE: usize = 256;
+
+#[derive(Debug, Error)]
+pub enum JobError {
+    #[error("Job not found: {0}")]
+    NotFound(Uuid),
+
+    #[error("Job {0} is already {1}")]
+    Finished(Uuid, JobStatus),
+
+    #[error("Job {0} is being run by another process")]
+    Busy(Uuid),
+
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum JobStatus {
+    /// Created but never started
+    Pending,
+    /// Started and not finished. A job whose process died stays running
+    /// until it is resumed, which is allowed once its lease has expired.
+    Running,
+    Completed,
+    Cancelled,
+}
+
+impl JobStatus {
+    /// Whether the job still has work that `resume` would pick up
+    pub fn is_resumable(&self) -> bool {
+        matches!(self, JobStatus::Pending | JobStatus::Running)
+    }
+}
+
+impl fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            JobStatus::Pending => "pending",
+            JobStatus::Running => "running",
+            JobStatus::Completed => "completed",
+            JobStatus::Cancelled => "cancelled",
+        })
+    }
+}
+
+/// A job along with its progress.
+#[derive(Debug, Serialize)]
+pub struct Job {
+    pub id: Uuid,
+    pub status: JobStatus,
+    pub source: String,
+    pub total: i64,
+    /// Files that have been processed, whatever the outcome
+    pub done: i64,
+    pub failed: i64,
+    pub created_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+/// Persist a new job for the given files. The job is pending until run.
+pub async fn create_job(
+    pool: &PgPool,
+    source: &str,
+    files: &[PathBuf],
+    config: &IngestConfig,
+) -> Result<Uuid, JobError> {
+    let job_id = Uuid::new_v4();
+    let paths: Vec<String> = files
+        .iter()
+        .map(|path| path.to_string_lossy().to_string())
+        .collect();
+    let config = serde_json::to_value(config).map_err(anyhow::Error::from)?;
+
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!(
+        "INSERT INTO ingest_jobs (id, source, config) VALUES ($1, $2, $3)",
+        job_id,
+        source,
+        config
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO ingest_job_files (job_id, file_path)
+        SELECT $1, file_path FROM UNNEST($2::text[]) AS file_path
+        ON CONFLICT DO NOTHING
+        "#,
+        job_id,
+        &paths
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    info!("Created ingest job {} with {} files", job_id, paths.len());
+    Ok(job_id)
+}
+
+/// List every job, newest first.
+pub async fn list_jobs(pool: &PgPool) -> Result<Vec<Job>, JobError> {
+    fetch_jobs(pool, None).await
+}
+
+pub async fn get_job(pool: &PgPool, job_id: Uuid) -> Result<Job, JobError> {
+    fetch_jobs(pool, Some(job_id))
+        .await?
+        .pop()
+        .ok_or(JobError::NotFound(job_id))
+}
+
+/// Jobs with their progress, newest first: all of them, or just `job_id`.
+async fn fetch_jobs(pool: &PgPool, job_id: Option<Uuid>) -> Result<Vec<Job>, JobError> {
+    let jobs = sqlx::query_as!(
+        Job,
+        r#"
+        SELECT j.id, j.status as "status: JobStatus", j.source,
+               COUNT(f.file_path) as "total!",
+               COUNT(f.file_path) FILTER (WHERE f.status <> 'pending') as "done!",
+               COUNT(f.file_path) FILTER (WHERE f.status = 'failed') as "failed!",
+               j.created_at, j.updated_at, j.finished_at
+        FROM ingest_jobs j
+        LEFT JOIN ingest_job_files f ON f.job_id = j.id
+        WHERE ($1::uuid IS NULL OR j.id = $1)
+        GROUP BY j.id
+        ORDER BY j.created_at DESC
+        "#,
+        job_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(jobs)
+}
+
+/// Cancel a job that hasn't finished. A process running it stops starting
+/// new files shortly afterwards; its remaining files stay pending.
+pub async fn cancel_job(pool: &PgPool, job_id: Uuid) -> Result<Job, JobError> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE ingest_jobs
+        SET status = 'cancelled', updated_at = CURRENT_TIMESTAMP, finished_at = CURRENT_TIMESTAMP
+        WHERE id = $1 AND status IN ('pending', 'running')
+        "#,
+        job_id
+    )
+    .execute(pool)
+    .await?;
+
+    let job = get_job(pool, job_id).await?;
+    if result.rows_affected() == 0 {
+        return Err(JobError::Finished(job_id, job.status));
+    }
+
+    info!("Cancelled ingest job {}", job_id);
+    Ok(job)
+}
+
+/// A job claimed by this process, ready to run.
+///
+/// The claim is a lease: the running job refreshes it every
+/// [`POLL_INTERVAL`], and another process may only take the job over once it
+/// hasn't been refreshed for [`LEASE_TIMEOUT`].
+pub struct JobLease {
+    pub job_id: Uuid,
+    runner_id: Uuid,
+    config: IngestConfig,
+}
+
+/// Claim a pending job, or a running one whose runner has stopped refreshing
+/// its lease. Fails with [`JobError::Busy`] while another process runs it.
+pub async fn claim_job(pool: &PgPool, job_id: Uuid) -> Result<JobLease, JobError> {
+    let runner_id = Uuid::new_v4();
+
+    let config = sqlx::query_scalar!(
+        r#"
+        UPDATE ingest_jobs
+        SET status = 'running', runner_id = $2, updated_at = CURRENT_TIMESTAMP
+        WHERE id = $1
+          AND (status = 'pending'
+               OR (status = 'running'
+                   AND (runner_id IS NULL
+                        OR updated_at < CURRENT_TIMESTAMP - make_interval(secs => $3))))
+        RETURNING config
+        "#,
+        job_id,
+        runner_id,
+        LEASE_TIMEOUT.as_secs_f64()
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    let Some(config) = config else {
+        let job = get_job(pool, job_id).await?;
+        return Err(match job.status {
+            JobStatus::Running => JobError::Busy(job_id),
+            status => JobError::Finished(job_id, status),
+        });
+    };
+    let config = serde_json::from_value(config).map_err(anyhow::Error::from)?;
+
+    Ok(JobLease {
+        job_id,
+        runner_id,
+        config,
+    })
+}
+
+/// Run, or resume, a job: every file still pending is pushed through the
+/// ingest pipeline and its outcome recorded as it completes, so an
+/// interrupted run picks up where it stopped.
+///
+/// `on_result` is called for every file processed by this run.
+pub async fn run_job<F>(
+    job_id: Uuid,
+    state: &AppState,
+    on_result: F,
+) -> Result<IngestSummary, JobError>
+where
+    F: FnMut(&FileResult),
+{
+    let lease = claim_job(&state.db_pool, job_id).await?;
+    run_claimed_job(lease, state, on_result).await
+}
+
+/// Run a job claimed with [`claim_job`], releasing it when done.
+///
+/// If the lease is lost, because the job was cancelled or another process
+/// took it over, no further files are started.
+pub async fn run_claimed_job<F>(
+    lease: JobLease,
+    state: &AppState,
+    on_result: F,
+) -> Result<IngestSummary, JobError>
+where
+    F: FnMut(&FileResult),
+{
+    let summary = run_leased(&lease, state, on_result).await;
+
+    // Let the job be resumed straight away rather than once the lease expires
+    let released = sqlx::query!(
+        "UPDATE ingest_jobs SET runner_id = NULL WHERE id = $1 AND runner_id = $2",
+        lease.job_id,
+        lease.runner_id
+    )
+    .execute(&state.db_pool)
+    .await;
+    if let Err(e) = released {
+        warn!("Failed to release job {}: {}", lease.job_id, e);
+    }
+
+    summary
+}
+
+async fn run_leased<F>(
+    lease: &JobLease,
+    state: &AppState,
+    mut on_result: F,
+) -> Result<IngestSummary, JobError>
+where
+    F: FnMut(&FileResult),
+{
+    let pool = &state.db_pool;
+    let job_id = lease.job_id;
+
+    let files: Vec<PathBuf> = sqlx::query_scalar!(
+        r#"
+        SELECT file_path FROM ingest_job_files
+        WHERE job_id = $1 AND status = 'pending'
+        ORDER BY file_path
+        "#,
+        job_id
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(PathBuf::from)
+    .collect();
+
+    info!(
+        "Running ingest job {} ({} files pending)",
+        job_id,
+        files.len()
+    );
+
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let lease_keeper = tokio::spawn(keep_lease(
+        pool.clone(),
+        job_id,
+        lease.runner_id,
+        Arc::clone(&cancelled),
+    ));
+
+    let (record_tx, record_rx) = mpsc::unbounded_channel();
+    let recorder = tokio::spawn(record_results(pool.clone(), job_id, record_rx));
+
+    let summary = pipeline::run(
+        files,
+        state,
+        &lease.config,
+        Arc::clone(&cancelled),
+        |result| {
+            let _ = record_tx.send(result.clone());
+            on_result(result);
+        },
+    )
+    .await;
+
+    drop(record_tx);
+    lease_keeper.abort();
+    recorder.await.map_err(anyhow::Error::from)??;
+    let summary = summary?;
+
+    if !cancelled.load(Ordering::Relaxed) {
+        sqlx::query!(
+            r#"
+            UPDATE ingest_jobs
+            SET status = 'completed', updated_at = CURRENT_TIMESTAMP, finished_at = CURRENT_TIMESTAMP
+            WHERE id = $1 AND status = 'running' AND runner_id = $2
+            "#,
+            job_id,
+            lease.runner_id
+        )
+        .execute(pool)
+        .await?;
+        info!("Completed ingest job {}", job_id);
+    }
+
+    Ok(summary)
+}
+
+/// Refresh the lease on a running job until it is lost, then flag the run
+/// as cancelled.
+async fn keep_lease(pool: PgPool, job_id: Uuid, runner_id: Uuid, cancelled: Arc<AtomicBool>) {
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let refreshed = sqlx::query!(
+            r#"
+            UPDATE ingest_jobs
+            SET updated_at = CURRENT_TIMESTAMP
+            WHERE id = $1 AND runner_id = $2 AND status = 'running'
+            "#,
+            job_id,
+            runner_id
+        )
+        .execute(&pool)
+        .await;
+
+        match refreshed {
+            Ok(result) if result.rows_affected() == 0 => {
+                info!(
+                    "Ingest job {} was cancelled or taken over, stopping",
+                    job_id
+                );
+                cancelled.store(true, Ordering::Relaxed);
+                return;
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Failed to refresh lease on job {}: {}", job_id, e),
+        }
+    }
+}
+
+/// Write per-file results in batches as they arrive.
+async fn record_results(
+    pool: PgPool,
+    job_id: Uuid,
+    mut results: mpsc::UnboundedReceiver<FileResult>,
+) -> Result<(), JobError> {
+    let mut batch = Vec::with_capacity(RECORD_BATCH_SIZE);
+
+    while results.recv_many(&mut batch, RECORD_BATCH_SIZE).await > 0 {
+        let mut paths = Vec::with_capacity(batch.len());
+        let mut statuses = Vec::with_capacity(batch.len());
+        let mut media_ids = Vec::with_capacity(batch.len());
+        let mut errors = Vec::with_capacity(batch.len());
+
+        for result in batch.drain(..) {
+            let (path, status, media_id, error) = match result {
+                FileResult::Ingested { path, outcome } => {
+                    let status = match outcome {
+                        IngestOutcome::New(_) => "new",
+                        IngestOutcome::Moved(_) => "moved",
+                        IngestOutcome::Skipped(_) => "skipped",
+                    };
+                    (path, status, Some(outcome.media_id()), None)
+                }
+                FileResult::Failed {
+                    path, stage, error, ..
+                } => (path, "failed", None, Some(format!("[{}] {}", stage, error))),
+            };
+            paths.push(path.to_string_lossy().to_string());
+            statuses.push(status.to_string());
+            media_ids.push(media_id);
+            errors.push(error);
+        }
+
+        sqlx::query!(
+            r#"
+            UPDATE ingest_job_files f
+            SET status = u.status, media_id = u.media_id, error = u.error
+            FROM UNNEST($2::text[], $3::text[], $4::uuid[], $5::text[])
+                AS u(file_path, status, media_id, error)
+            WHERE f.job_id = $1 AND f.file_path = u.file_path
+            "#,
+            job_id,
+            &paths,
+            &statuses,
+            &media_ids as &[Option<Uuid>],
+            &errors as &[Option<String>]
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query!(
+            "UPDATE ingest_jobs SET updated_at = CURRENT_TIMESTAMP WHERE id = $1",
+            job_id
+        )
+        .execute(&pool)
+        .await?;
+    }
+
+    Ok(())
+}
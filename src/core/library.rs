@@ -0,0 +1,182 @@
+use crate::core::config::StorageConfig;
+use crate::core::media::{modified_at, MediaDetails};
+use anyhow::{Context, Result};
+use chrono::{Datelike, NaiveDateTime, Utc};
+use image::ImageFormat;
+use std::path::{Path, PathBuf};
+
+/// Placeholders accepted in `storage.library_layout`
+const LAYOUT_PLACEHOLDERS: &[&str] = &["year", "month", "day", "hash", "ext"];
+
+/// Values substituted into a library layout.
+#[derive(Debug, Clone)]
+pub struct LayoutFields<'a> {
+    pub date: NaiveDateTime,
+    pub hash: &'a str,
+    pub ext: &'a str,
+}
+
+/// Check a library layout only uses known placeholders and includes
+/// `{hash}`, so two different files can never map to the same path.
+pub fn validate_layout(layout: &str) -> Result<(), String> {
+    let placeholders = parse_placeholders(layout)?;
+
+    if let Some(unknown) = placeholders
+        .iter()
+        .find(|name| !LAYOUT_PLACEHOLDERS.contains(name))
+    {
+        return Err(format!(
+            "Unknown placeholder {{{}}} in library layout {:?}",
+            unknown, layout
+        ));
+    }
+
+    if !placeholders.contains(&"hash") {
+        return Err(format!("Library layout {:?} must include {{hash}}", layout));
+    }
+
+    Ok(())
+}
+
+fn parse_placeholders(layout: &str) -> Result<Vec<&str>, String> {
+    let mut placeholders = Vec::new();
+    let mut rest = layout;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("Unclosed placeholder in library layout {:?}", layout))?;
+        placeholders.push(&rest[start + 1..start + end]);
+        rest = &rest[start + end + 1..];
+    }
+    Ok(placeholders)
+}
+
+/// Substitute the placeholders of a library layout:
+///
+/// - `{year}`, `{month}`, `{day}`: capture date, zero padded
+/// - `{hash}`: hex SHA-256 of the file contents
+/// - `{ext}`: canonical extension of the detected format
+pub fn render_layout(layout: &str, fields: &LayoutFields) -> String {
+    layout
+        .replace("{year}", &format!("{:04}", fields.date.year()))
+        .replace("{month}", &format!("{:02}", fields.date.month()))
+        .replace("{day}", &format!("{:02}", fields.date.day()))
+        .replace("{hash}", fields.hash)
+        .replace("{ext}", fields.ext)
+}
+
+/// Where an original belongs in the library.
+///
+/// Files are dated by their EXIF capture time, falling back to the file's
+/// modification time and then the current time.
+pub fn library_path(storage: &StorageConfig, details: &MediaDetails) -> PathBuf {
+    let date = details
+        .exif
+        .as_ref()
+        .and_then(|exif| exif.captured_at)
+        .or_else(|| details.modified_at.map(|modified| modified.naive_utc()))
+        .unwrap_or_else(|| Utc::now().naive_utc());
+
+    let ext = ImageFormat::from_mime_type(&details.content_type)
+        .and_then(|format| format.extensions_str().first().copied())
+        .unwrap_or("bin");
+
+    storage.media_path.join(render_layout(
+        &storage.library_layout,
+        &LayoutFields {
+            date,
+            hash: &details.content_hash,
+            ext,
+        },
+    ))
+}
+
+/// Copy an original into the library and point `details` at the copy.
+///
+/// Returns the path the file was imported from. The source is never touched
+/// here; in move mode it is deleted only once the media row is committed.
+pub fn import_original(details: &mut MediaDetails, storage: &StorageConfig) -> Result<PathBuf> {
+    let source = PathBuf::from(&details.file_path);
+    let destination = library_path(storage, details);
+
+    // The layout is content-addressed, so an existing file already holds
+    // these exact bytes
+    if !destination.exists() {
+        copy_atomically(&source, &destination)
+            .with_context(|| format!("Failed to copy {:?} to {:?}", source, destination))?;
+    }
+
+    details.file_path = destination.to_string_lossy().to_string();
+    details.modified_at = modified_at(&destination);
+    Ok(source)
+}
+
+/// Whether the file at `path` belongs to the library: uploads and imported
+/// copies live under `storage.media_path`, while referenced originals stay
+/// wherever they were ingested from and are never deleted by the app.
+pub fn is_library_file(storage: &StorageConfig, path: &Path) -> bool {
+    let absolute = |path: &Path| std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    absolute(path).starts_with(absolute(&storage.media_path))
+}
+
+fn copy_atomically(source: &Path, destination: &Path) -> std::io::Result<()> {
+    if let Some(parent) = destination.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let tmp_path = destination.with_extension("tmp");
+    std::fs::copy(source, &tmp_path)?;
+    std::fs::rename(&tmp_path, destination)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn test_render_layout() {
+        let fields = LayoutFields {
+            date: NaiveDate::from_ymd_opt(2024, 3, 7)
+                .unwrap()
+                .and_hms_opt(12, 0, 0)
+                .unwrap(),
+            hash: "abc123",
+            ext: "jpg",
+        };
+        assert_eq!(
+            render_layout("{year}/{month}/{day}/{hash}.{ext}", &fields),
+            "2024/03/07/abc123.jpg"
+        );
+    }
+
+    #[test]
+    fn test_is_library_file() {
+        let storage = StorageConfig {
+            media_path: PathBuf::from("/srv/media"),
+            thumbnail_sizes: Vec::new(),
+            library_layout: "{hash}.{ext}".to_string(),
+            library_roots: Vec::new(),
+        };
+        assert!(is_library_file(
+            &storage,
+            Path::new("/srv/media/2024/abc.jpg")
+        ));
+        assert!(!is_library_file(
+            &storage,
+            Path::new("/home/me/Photos/abc.jpg")
+        ));
+        assert!(!is_library_file(
+            &storage,
+            Path::new("/srv/media-old/abc.jpg")
+        ));
+    }
+
+    #[test]
+    fn test_validate_layout() {
+        assert!(validate_layout("{year}/{month}/{hash}.{ext}").is_ok());
+        assert!(validate_layout("{year}/{month}/photo.{ext}").is_err());
+        assert!(validate_layout("{year}/{camera}/{hash}.{ext}").is_err());
+        assert!(validate_layout("{year/{hash}.{ext}").is_err());
+    }
+}
@@ -1,6 +1,47 @@
-use std::{error::Error, path::PathBuf};
+use std::{
+    error::Error,
+    io::Read,
+    path::{Path, PathBuf},
+};
 
-use image::DynamicImage;
+use chrono::{DateTime, SubsecRound, Utc};
+use image::{metadata::Orientation, DynamicImage, ImageFormat};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use super::metadata::{read_exif, ExifMetadata};
+
+/// Image formats accepted for ingestion
+const SUPPORTED_FORMATS: &[ImageFormat] = &[
+    ImageFormat::Jpeg,
+    ImageFormat::Png,
+    ImageFormat::Gif,
+    ImageFormat::WebP,
+    ImageFormat::Tiff,
+    ImageFormat::Bmp,
+];
+
+/// Number of leading bytes read when sniffing a file's format
+const SNIFF_LEN: usize = 32;
+
+/// Reasons a file is rejected before decoding
+#[derive(Debug, Error)]
+pub enum MediaError {
+    #[error("could not read file: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("unrecognised file format")]
+    UnknownFormat,
+
+    #[error("unsupported image format {0:?}")]
+    UnsupportedFormat(ImageFormat),
+
+    #[error("file extension .{extension} does not match its {detected:?} content")]
+    ExtensionMismatch {
+        extension: String,
+        detected: ImageFormat,
+    },
+}
 
 /// Media details extracted from a file path
 pub struct MediaDetails {
@@ -8,24 +49,199 @@ pub struct MediaDetails {
     pub filename: String,
     pub file_path: String,
     pub file_size: u64,
+    /// MIME type detected from the file's magic bytes
+    pub content_type: String,
+    /// Hex-encoded SHA-256 of the file bytes
+    pub content_hash: String,
+    /// EXIF metadata, if the file has any
+    pub exif: Option<ExifMetadata>,
+    /// Modification time of the file, used to spot changes on rescan
+    pub modified_at: Option<DateTime<Utc>>,
 }
 
 /// Extract the file details from the path
-/// Including the image, filename, file path, and file size
+/// Including the image, filename, file path, file size, content hash and EXIF metadata
 pub fn extract_media_details_from_path(path: &PathBuf) -> Result<MediaDetails, Box<dyn Error>> {
-    let image = image::ImageReader::open(path)?.decode()?;
+    let bytes = std::fs::read(path)?;
+    let content_hash = hash_bytes(&bytes);
+    let format = detect_format(path, &bytes)?;
+    let exif = read_exif(&bytes);
+    let mut image = image::load_from_memory_with_format(&bytes, format)?;
+    if let Some(orientation) = exif.as_ref().and_then(|exif| exif.orientation) {
+        apply_exif_orientation(&mut image, orientation);
+    }
     let filename = path
         .file_name()
         .ok_or("Image path not found")?
         .to_string_lossy()
         .to_string();
     let file_path = path.to_string_lossy().to_string();
-    let file_size = path.metadata()?.len();
+    let file_size = bytes.len() as u64;
+    let modified_at = modified_at(path);
 
     Ok(MediaDetails {
         image,
         filename,
         file_path,
         file_size,
+        content_type: format.to_mime_type().to_string(),
+        content_hash,
+        exif,
+        modified_at,
     })
 }
+
+/// Modification time of a file, truncated to the microsecond precision the
+/// database stores so the two can be compared directly.
+pub fn modified_at(path: &Path) -> Option<DateTime<Utc>> {
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    Some(DateTime::<Utc>::from(modified).trunc_subsecs(6))
+}
+
+/// Hex-encoded SHA-256 digest of the given bytes
+pub fn hash_bytes(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+/// Rotate and/or flip an image so it is upright, according to its raw EXIF
+/// orientation value. Unknown values leave the image untouched.
+pub fn apply_exif_orientation(image: &mut DynamicImage, orientation: u32) {
+    if let Some(orientation) = u8::try_from(orientation)
+        .ok()
+        .and_then(Orientation::from_exif)
+    {
+        image.apply_orientation(orientation);
+    }
+}
+
+/// Detect a file's image format from its magic bytes.
+///
+/// The format must be one we support, and if the file has an extension that
+/// names an image format it must agree with the content.
+pub fn detect_format(path: &Path, bytes: &[u8]) -> Result<ImageFormat, MediaError> {
+    let detected = supported_format(bytes)?;
+
+    if let Some(extension) = path.extension() {
+        if let Some(claimed) = ImageFormat::from_extension(extension) {
+            if claimed != detected {
+                return Err(MediaError::ExtensionMismatch {
+                    extension: extension.to_string_lossy().to_string(),
+                    detected,
+                });
+            }
+        }
+    }
+
+    Ok(detected)
+}
+
+/// The supported image format the leading bytes of a file belong to.
+fn supported_format(bytes: &[u8]) -> Result<ImageFormat, MediaError> {
+    let detected = image::guess_format(bytes).map_err(|_| MediaError::UnknownFormat)?;
+
+    if !SUPPORTED_FORMATS.contains(&detected) {
+        return Err(MediaError::UnsupportedFormat(detected));
+    }
+
+    Ok(detected)
+}
+
+/// Sniff a file's header for a supported image format, without decoding it.
+/// The error says why the file isn't one.
+pub fn sniff_format(path: &Path) -> Result<ImageFormat, MediaError> {
+    let mut header = Vec::with_capacity(SNIFF_LEN);
+    std::fs::File::open(path)?
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut header)?;
+
+    supported_format(&header)
+}
+
+/// Check whether a file looks like a supported image by sniffing its header.
+pub fn is_supported_image(path: &Path) -> bool {
+    sniff_format(path).is_ok()
+}
+
+/// Every supported image below a directory, sorted. Directories in `exclude`
+/// are not descended into and unreadable directories are skipped.
+pub fn images_under(dir: &Path, exclude: &[PathBuf]) -> Vec<PathBuf> {
+    let mut images = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                if !exclude.contains(&path) {
+                    pending.push(path);
+                }
+            } else if is_supported_image(&path) {
+                images.push(path);
+            }
+        }
+    }
+
+    images.sort();
+    images
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG_HEADER: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    #[test]
+    fn test_apply_exif_orientation_rotates_portrait_photos() {
+        // Orientation 6 means the camera was rotated 90 degrees clockwise
+        let mut image = DynamicImage::new_rgb8(4, 3);
+        apply_exif_orientation(&mut image, 6);
+        assert_eq!((image.width(), image.height()), (3, 4));
+    }
+
+    #[test]
+    fn test_apply_exif_orientation_ignores_unknown_values() {
+        let mut image = DynamicImage::new_rgb8(4, 3);
+        apply_exif_orientation(&mut image, 42);
+        assert_eq!((image.width(), image.height()), (4, 3));
+    }
+
+    #[test]
+    fn test_detect_format_from_magic_bytes() {
+        let format = detect_format(Path::new("photo.png"), PNG_HEADER).unwrap();
+        assert_eq!(format, ImageFormat::Png);
+        assert_eq!(format.to_mime_type(), "image/png");
+    }
+
+    #[test]
+    fn test_detect_format_ignores_missing_extension() {
+        assert!(detect_format(Path::new("photo"), PNG_HEADER).is_ok());
+    }
+
+    #[test]
+    fn test_detect_format_rejects_extension_mismatch() {
+        assert!(matches!(
+            detect_format(Path::new("photo.jpg"), PNG_HEADER),
+            Err(MediaError::ExtensionMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_detect_format_names_unsupported_formats() {
+        assert!(matches!(
+            detect_format(Path::new("photo.qoi"), b"qoif\0\0\0\x08\0\0\0\x08\x03\0"),
+            Err(MediaError::UnsupportedFormat(ImageFormat::Qoi))
+        ));
+    }
+
+    #[test]
+    fn test_detect_format_rejects_unknown_content() {
+        assert!(matches!(
+            detect_format(Path::new("notes.txt"), b"hello world"),
+            Err(MediaError::UnknownFormat)
+        ));
+    }
+}
@@ -0,0 +1,267 @@
+use chrono::{NaiveDate, NaiveDateTime};
+use exif::{DateTime, Exif, In, Reader, Tag, Value};
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+
+/// EXIF fields extracted from an image, stored in `media.metadata`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ExifMetadata {
+    /// Local capture time as recorded by the camera
+    pub captured_at: Option<NaiveDateTime>,
+    /// Offset from UTC of `captured_at` in minutes, if the camera recorded it
+    pub utc_offset_minutes: Option<i16>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub lens_model: Option<String>,
+    /// Exposure time in seconds
+    pub exposure_time: Option<f64>,
+    pub f_number: Option<f64>,
+    pub iso: Option<u32>,
+    pub focal_length_mm: Option<f64>,
+    /// Raw EXIF orientation (1-8)
+    pub orientation: Option<u32>,
+    pub gps: Option<GpsCoordinates>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct GpsCoordinates {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Metres above sea level
+    pub altitude: Option<f64>,
+}
+
+/// Parse the EXIF block of an image, if it has one.
+///
+/// Fields that are missing or malformed are left as `None` rather than
+/// failing the whole image.
+pub fn read_exif(bytes: &[u8]) -> Option<ExifMetadata> {
+    let exif = Reader::new()
+        .read_from_container(&mut Cursor::new(bytes))
+        .ok()?;
+    let capture_time = capture_time(&exif);
+
+    Some(ExifMetadata {
+        captured_at: capture_time.map(|(time, _)| time),
+        utc_offset_minutes: capture_time.and_then(|(_, offset)| offset),
+        camera_make: ascii_field(&exif, Tag::Make),
+        camera_model: ascii_field(&exif, Tag::Model),
+        lens_model: ascii_field(&exif, Tag::LensModel),
+        exposure_time: rational_field(&exif, Tag::ExposureTime),
+        f_number: rational_field(&exif, Tag::FNumber),
+        iso: uint_field(&exif, Tag::PhotographicSensitivity),
+        focal_length_mm: rational_field(&exif, Tag::FocalLength),
+        orientation: uint_field(&exif, Tag::Orientation),
+        gps: gps_coordinates(&exif),
+    })
+}
+
+fn ascii_field(exif: &Exif, tag: Tag) -> Option<String> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(values) => {
+            let value = String::from_utf8_lossy(values.first()?);
+            let value = value.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+            (!value.is_empty()).then(|| value.to_string())
+        }
+        _ => None,
+    }
+}
+
+fn raw_ascii(exif: &Exif, tag: Tag) -> Option<&[u8]> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(values) => values.first().map(Vec::as_slice),
+        _ => None,
+    }
+}
+
+fn rational_field(exif: &Exif, tag: Tag) -> Option<f64> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(values) => values.first().filter(|r| r.denom != 0).map(|r| r.to_f64()),
+        _ => None,
+    }
+}
+
+fn uint_field(exif: &Exif, tag: Tag) -> Option<u32> {
+    exif.get_field(tag, In::PRIMARY)?.value.get_uint(0)
+}
+
+fn capture_time(exif: &Exif) -> Option<(NaiveDateTime, Option<i16>)> {
+    let raw = raw_ascii(exif, Tag::DateTimeOriginal).or_else(|| raw_ascii(exif, Tag::DateTime))?;
+    let mut datetime = DateTime::from_ascii(raw).ok()?;
+    if let Some(offset) = raw_ascii(exif, Tag::OffsetTimeOriginal) {
+        let _ = datetime.parse_offset(offset);
+    }
+
+    let time = NaiveDate::from_ymd_opt(
+        datetime.year.into(),
+        datetime.month.into(),
+        datetime.day.into(),
+    )?
+    .and_hms_nano_opt(
+        datetime.hour.into(),
+        datetime.minute.into(),
+        datetime.second.into(),
+        datetime.nanosecond.unwrap_or(0),
+    )?;
+
+    Some((time, datetime.offset))
+}
+
+fn gps_coordinates(exif: &Exif) -> Option<GpsCoordinates> {
+    let latitude = gps_degrees(exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, b'S')?;
+    let longitude = gps_degrees(exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, b'W')?;
+
+    let altitude = rational_field(exif, Tag::GPSAltitude).map(|altitude| {
+        // A reference of 1 means the altitude is below sea level
+        if uint_field(exif, Tag::GPSAltitudeRef) == Some(1) {
+            -altitude
+        } else {
+            altitude
+        }
+    });
+
+    Some(GpsCoordinates {
+        latitude,
+        longitude,
+        altitude,
+    })
+}
+
+/// Read a degrees/minutes/seconds GPS field as signed decimal degrees.
+fn gps_degrees(exif: &Exif, tag: Tag, ref_tag: Tag, negative_ref: u8) -> Option<f64> {
+    let dms = match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(values) if values.len() >= 3 && values.iter().all(|r| r.denom != 0) => {
+            [values[0].to_f64(), values[1].to_f64(), values[2].to_f64()]
+        }
+        _ => return None,
+    };
+
+    let degrees = dms_to_degrees(dms);
+    match raw_ascii(exif, ref_tag).and_then(|r| r.first()) {
+        Some(&r) if r.eq_ignore_ascii_case(&negative_ref) => Some(-degrees),
+        _ => Some(degrees),
+    }
+}
+
+fn dms_to_degrees([degrees, minutes, seconds]: [f64; 3]) -> f64 {
+    degrees + minutes / 60.0 + seconds / 3600.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+    use exif::experimental::Writer;
+    use exif::{Field, Rational};
+
+    fn ascii(tag: Tag, value: &str) -> Field {
+        Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![value.as_bytes().to_vec()]),
+        }
+    }
+
+    fn dms(tag: Tag, [degrees, minutes, seconds]: [u32; 3]) -> Field {
+        let rational = |num| Rational { num, denom: 1 };
+        Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value: Value::Rational(vec![
+                rational(degrees),
+                rational(minutes),
+                rational(seconds),
+            ]),
+        }
+    }
+
+    /// A JPEG holding nothing but an EXIF block with `fields`, which is all
+    /// the reader looks at.
+    fn jpeg_with_exif(fields: &[Field]) -> Vec<u8> {
+        let mut writer = Writer::new();
+        for field in fields {
+            writer.push_field(field);
+        }
+        let mut tiff = Cursor::new(Vec::new());
+        writer.write(&mut tiff, false).unwrap();
+        let tiff = tiff.into_inner();
+
+        // SOI, then an APP1 segment whose length counts itself and the header
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        jpeg.extend(((tiff.len() + 8) as u16).to_be_bytes());
+        jpeg.extend(b"Exif\0\0");
+        jpeg.extend(tiff);
+        jpeg.extend([0xFF, 0xD9]);
+        jpeg
+    }
+
+    #[test]
+    fn test_dms_to_degrees() {
+        assert_relative_eq!(dms_to_degrees([51.0, 30.0, 36.0]), 51.51, epsilon = 1e-9);
+    }
+
+    #[test]
+    fn test_read_exif_without_exif_block() {
+        assert_eq!(read_exif(b"not an image"), None);
+    }
+
+    #[test]
+    fn test_read_exif_capture_time_and_southern_western_coordinates() {
+        let fields = [
+            ascii(Tag::Make, "Canon"),
+            ascii(Tag::DateTimeOriginal, "2024:03:07 12:34:56"),
+            ascii(Tag::OffsetTimeOriginal, "+02:00"),
+            dms(Tag::GPSLatitude, [33, 51, 36]),
+            ascii(Tag::GPSLatitudeRef, "S"),
+            dms(Tag::GPSLongitude, [70, 39, 0]),
+            ascii(Tag::GPSLongitudeRef, "W"),
+        ];
+
+        let exif = read_exif(&jpeg_with_exif(&fields)).unwrap();
+        assert_eq!(exif.camera_make.as_deref(), Some("Canon"));
+        assert_eq!(
+            exif.captured_at,
+            NaiveDate::from_ymd_opt(2024, 3, 7).and_then(|date| date.and_hms_opt(12, 34, 56))
+        );
+        assert_eq!(exif.utc_offset_minutes, Some(120));
+
+        let gps = exif.gps.unwrap();
+        assert_relative_eq!(gps.latitude, -33.86, epsilon = 1e-9);
+        assert_relative_eq!(gps.longitude, -70.65, epsilon = 1e-9);
+        assert_eq!(gps.altitude, None);
+    }
+
+    #[test]
+    fn test_read_exif_without_refs_or_original_time() {
+        let fields = [
+            ascii(Tag::DateTime, "2023:12:31 23:59:59"),
+            dms(Tag::GPSLatitude, [51, 30, 36]),
+            dms(Tag::GPSLongitude, [0, 7, 39]),
+        ];
+
+        let exif = read_exif(&jpeg_with_exif(&fields)).unwrap();
+        // Without DateTimeOriginal the modification time is used
+        assert_eq!(
+            exif.captured_at,
+            NaiveDate::from_ymd_opt(2023, 12, 31).and_then(|date| date.and_hms_opt(23, 59, 59))
+        );
+        assert_eq!(exif.utc_offset_minutes, None);
+
+        // A missing reference leaves the coordinates positive
+        let gps = exif.gps.unwrap();
+        assert_relative_eq!(gps.latitude, 51.51, epsilon = 1e-9);
+        assert_relative_eq!(gps.longitude, 0.1275, epsilon = 1e-9);
+    }
+
+    #[test]
+    fn test_read_exif_needs_both_coordinates() {
+        let fields = [
+            dms(Tag::GPSLatitude, [51, 30, 36]),
+            ascii(Tag::GPSLatitudeRef, "N"),
+        ];
+
+        let exif = read_exif(&jpeg_with_exif(&fields)).unwrap();
+        assert_eq!(exif.gps, None);
+        assert_eq!(exif.captured_at, None);
+    }
+}
@@ -1,6 +1,21 @@
 pub mod config;
 pub mod db;
 pub mod embedding;
+pub mod failures;
+#[cfg(test)]
+pub mod fake;
 pub mod ingest;
+pub mod jobs;
+pub mod library;
 pub mod media;
+pub mod metadata;
+pub mod models;
+pub mod pipeline;
+pub mod preprocessing;
+pub mod rescan;
+pub mod search;
+pub mod siglip;
 pub mod state;
+pub mod tags;
+pub mod thumbnails;
+pub mod watch;
@@ -0,0 +1,196 @@
+use crate::core::config::{EmbeddingBackend, EmbeddingConfig};
+use crate::core::siglip;
+use anyhow::{anyhow, Context, Result};
+use hf_hub::api::sync::ApiBuilder;
+use hf_hub::{Cache, Repo, RepoType};
+use std::path::PathBuf;
+use tracing::{info, warn};
+
+const WEIGHTS_FILE: &str = "model.safetensors";
+const TOKENIZER_FILE: &str = "tokenizer.json";
+const PREPROCESSOR_FILE: &str = "preprocessor_config.json";
+
+const DEFAULT_REVISION: &str = "main";
+
+/// Revisions to use for hub models whose default branch has no
+/// `model.safetensors`
+const SAFETENSORS_REVISIONS: &[(&str, &str)] = &[
+    // The safetensors conversion of the weights was never merged into main
+    ("openai/clip-vit-base-patch32", "refs/pr/15"),
+];
+
+/// The files an embedding model is loaded from.
+#[derive(Debug, Clone)]
+pub struct ModelFiles {
+    pub weights: PathBuf,
+    pub tokenizer: PathBuf,
+    /// Image preprocessing parameters, if the model publishes them
+    pub preprocessor: Option<PathBuf>,
+}
+
+/// The hub repository configured for the embedding backend, falling back to
+/// the backend's reference weights. The test-only fake backend has none.
+pub fn hub_model_id(config: &EmbeddingConfig) -> Option<String> {
+    let default = match config.backend {
+        EmbeddingBackend::Clip => config.model.hub_id(),
+        EmbeddingBackend::Siglip => siglip::HUB_ID,
+        #[cfg(test)]
+        EmbeddingBackend::Fake => return None,
+    };
+    Some(
+        config
+            .model_id
+            .clone()
+            .unwrap_or_else(|| default.to_string()),
+    )
+}
+
+/// The revision of `model_id` to load: `embedding.revision` if set,
+/// otherwise one known to have `model.safetensors`, otherwise "main".
+pub fn hub_revision(config: &EmbeddingConfig, model_id: &str) -> String {
+    config.revision.clone().unwrap_or_else(|| {
+        SAFETENSORS_REVISIONS
+            .iter()
+            .find(|(id, _)| *id == model_id)
+            .map_or(DEFAULT_REVISION, |&(_, revision)| revision)
+            .to_string()
+    })
+}
+
+fn hub_repo(config: &EmbeddingConfig) -> Result<Repo> {
+    let model_id = hub_model_id(config)
+        .ok_or_else(|| anyhow!("The {:?} backend has no model files", config.backend))?;
+    let revision = hub_revision(config, &model_id);
+    Ok(Repo::with_revision(model_id, RepoType::Model, revision))
+}
+
+/// Locate the model files, preferring `model_path` and `tokenizer_path` and
+/// otherwise looking in the local Hugging Face cache. Files missing from the
+/// cache are downloaded.
+pub fn resolve_model_files(config: &EmbeddingConfig) -> Result<ModelFiles> {
+    let local = |path: &Option<String>| path.as_ref().map(PathBuf::from);
+
+    if let (Some(weights), Some(tokenizer)) =
+        (local(&config.model_path), local(&config.tokenizer_path))
+    {
+        // Local models may keep their preprocessor config next to the weights
+        let preprocessor = weights
+            .with_file_name(PREPROCESSOR_FILE)
+            .exists()
+            .then(|| weights.with_file_name(PREPROCESSOR_FILE));
+        return Ok(ModelFiles {
+            weights,
+            tokenizer,
+            preprocessor,
+        });
+    }
+
+    let repo = hub_repo(config)?;
+    let cache = Cache::default().repo(repo.clone());
+    let resolve = |override_path: &Option<String>, filename: &str| -> Result<PathBuf> {
+        if let Some(path) = local(override_path) {
+            return Ok(path);
+        }
+        if let Some(path) = cache.get(filename) {
+            return Ok(path);
+        }
+        download(&repo, filename).with_context(|| {
+            format!(
+                "{} of {} is not cached and could not be downloaded. Check that the \
+                 revision has the file (set embedding.revision to one that does, such as \
+                 a refs/pr/N safetensors conversion). On hosts without network access run \
+                 `semantic-gallery models fetch` somewhere that has it and copy the \
+                 Hugging Face cache over, or set embedding.model_path and \
+                 embedding.tokenizer_path.",
+                filename,
+                repo.url()
+            )
+        })
+    };
+
+    let preprocessor = match cache.get(PREPROCESSOR_FILE) {
+        Some(path) => Some(path),
+        None => optional_download(&repo, PREPROCESSOR_FILE),
+    };
+
+    Ok(ModelFiles {
+        weights: resolve(&config.model_path, WEIGHTS_FILE)?,
+        tokenizer: resolve(&config.tokenizer_path, TOKENIZER_FILE)?,
+        preprocessor,
+    })
+}
+
+/// Download the configured hub model into the local cache, ignoring any
+/// local path overrides, so later runs work offline.
+pub fn fetch_model_files(config: &EmbeddingConfig) -> Result<ModelFiles> {
+    let repo = hub_repo(config)?;
+    Ok(ModelFiles {
+        weights: download(&repo, WEIGHTS_FILE)?,
+        tokenizer: download(&repo, TOKENIZER_FILE)?,
+        preprocessor: optional_download(&repo, PREPROCESSOR_FILE),
+    })
+}
+
+/// Download a file not every model has, such as its preprocessor config.
+fn optional_download(repo: &Repo, filename: &str) -> Option<PathBuf> {
+    download(repo, filename).map_err(|e| warn!("{:#}", e)).ok()
+}
+
+fn download(repo: &Repo, filename: &str) -> Result<PathBuf> {
+    info!("Fetching {} from {}", filename, repo.url());
+    let api = ApiBuilder::new().with_progress(true).build()?;
+    let path = api
+        .repo(repo.clone())
+        .get(filename)
+        .with_context(|| format!("Failed to fetch {} from {}", filename, repo.url()))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::embedding::ClipVariant;
+
+    #[test]
+    fn test_hub_model_id_defaults_to_backend_weights() {
+        let mut config = EmbeddingConfig::default();
+        assert_eq!(
+            hub_model_id(&config).as_deref(),
+            Some("openai/clip-vit-base-patch32")
+        );
+
+        config.model = ClipVariant::VitLargePatch14;
+        assert_eq!(
+            hub_model_id(&config).as_deref(),
+            Some("openai/clip-vit-large-patch14")
+        );
+
+        config.model_id = Some("laion/CLIP-ViT-B-32-laion2B-s34B-b79K".to_string());
+        assert_eq!(
+            hub_model_id(&config).as_deref(),
+            Some("laion/CLIP-ViT-B-32-laion2B-s34B-b79K")
+        );
+
+        config.backend = EmbeddingBackend::Fake;
+        assert_eq!(hub_model_id(&config), None);
+    }
+
+    #[test]
+    fn test_hub_revision_pins_safetensors_weights() {
+        let mut config = EmbeddingConfig::default();
+        assert_eq!(
+            hub_revision(&config, "openai/clip-vit-base-patch32"),
+            "refs/pr/15"
+        );
+        assert_eq!(
+            hub_revision(&config, "openai/clip-vit-large-patch14"),
+            "main"
+        );
+
+        config.revision = Some("v1.0".to_string());
+        assert_eq!(
+            hub_revision(&config, "openai/clip-vit-base-patch32"),
+            "v1.0"
+        );
+    }
+}
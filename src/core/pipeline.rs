@@ -0,0 +1,671 @@
+use crate::core::config::{ImportMode, IngestConfig, StorageConfig};
+use crate::core::embedding::Embedder;
+use crate::core::failures::{self, FailureStage};
+use crate::core::ingest::{
+    find_existing_by_hash, finish_import, insert_media_batch, prepare_import, resolve_duplicate,
+    thumbnails_for, EmbeddedMedia, IngestOutcome,
+};
+use crate::core::media::{extract_media_details_from_path, MediaDetails};
+use crate::core::state::AppState;
+use anyhow::Result;
+use image::DynamicImage;
+use sqlx::postgres::PgPool;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+/// The result of pushing a single file through the pipeline.
+#[derive(Debug, Clone)]
+pub enum FileResult {
+    Ingested {
+        path: PathBuf,
+        outcome: IngestOutcome,
+    },
+    Failed {
+        path: PathBuf,
+        stage: FailureStage,
+        error: String,
+    },
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct IngestSummary {
+    pub new: usize,
+    pub moved: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    /// Failure counts keyed by the stage the files failed in
+    pub failed_by_stage: BTreeMap<FailureStage, usize>,
+}
+
+impl IngestSummary {
+    fn record(&mut self, result: &FileResult) {
+        match result {
+            FileResult::Ingested {
+                outcome: IngestOutcome::New(_),
+                ..
+            } => self.new += 1,
+            FileResult::Ingested {
+                outcome: IngestOutcome::Moved(_),
+                ..
+            } => self.moved += 1,
+            FileResult::Ingested {
+                outcome: IngestOutcome::Skipped(_),
+                ..
+            } => self.skipped += 1,
+            FileResult::Failed { stage, .. } => {
+                self.failed += 1;
+                *self.failed_by_stage.entry(*stage).or_default() += 1;
+            }
+        }
+    }
+}
+
+/// Number of file results written to `ingest_failures` per transaction
+const FAILURE_BATCH_SIZE: usize = 256;
+
+type ResultSender = mpsc::UnboundedSender<FileResult>;
+
+/// Content hashes handled during this run, so identical files within a
+/// single ingest don't both try to insert.
+///
+/// A hash only counts as stored once the batch carrying it is written. Until
+/// then later copies wait on that batch and share its outcome, so a failed
+/// batch can't leave them pointing at a row that was never inserted.
+#[derive(Debug, Default)]
+struct RunHashes {
+    stored: HashMap<String, Uuid>,
+    /// Copies of content whose first file is still on its way to the database
+    in_flight: HashMap<String, Vec<PathBuf>>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Claim {
+    /// The content was stored earlier in this run
+    Stored(Uuid),
+    /// The content is in a batch that hasn't been written yet
+    InFlight,
+    /// The content is new and the caller should ingest it
+    New,
+}
+
+impl RunHashes {
+    fn claim(&mut self, content_hash: &str, path: &Path) -> Claim {
+        if let Some(&media_id) = self.stored.get(content_hash) {
+            return Claim::Stored(media_id);
+        }
+
+        if let Some(copies) = self.in_flight.get_mut(content_hash) {
+            copies.push(path.to_path_buf());
+            return Claim::InFlight;
+        }
+
+        self.in_flight.insert(content_hash.to_string(), Vec::new());
+        Claim::New
+    }
+
+    /// Report the outcome of the file that claimed `content_hash`, followed by
+    /// the copies that were waiting on it.
+    fn settle(&mut self, content_hash: &str, result: FileResult, result_tx: &ResultSender) {
+        let copies = self.in_flight.remove(content_hash).unwrap_or_default();
+        let copy_result = |path| match &result {
+            FileResult::Ingested { outcome, .. } => FileResult::Ingested {
+                path,
+                outcome: IngestOutcome::Skipped(outcome.media_id()),
+            },
+            FileResult::Failed { stage, error, .. } => FileResult::Failed {
+                path,
+                stage: *stage,
+                error: error.clone(),
+            },
+        };
+        let copies: Vec<FileResult> = copies.into_iter().map(copy_result).collect();
+
+        if let FileResult::Ingested { outcome, .. } = &result {
+            self.stored
+                .insert(content_hash.to_string(), outcome.media_id());
+        }
+
+        let _ = result_tx.send(result);
+        for copy in copies {
+            let _ = result_tx.send(copy);
+        }
+    }
+
+    /// Fail every claimed file in `items`, given as `(content_hash, path)`.
+    fn fail_all(
+        &mut self,
+        items: Vec<(String, PathBuf)>,
+        stage: FailureStage,
+        error: &str,
+        result_tx: &ResultSender,
+    ) {
+        for (content_hash, path) in items {
+            let result = FileResult::Failed {
+                path,
+                stage,
+                error: error.to_string(),
+            };
+            self.settle(&content_hash, result, result_tx);
+        }
+    }
+}
+
+type SharedHashes = Arc<Mutex<RunHashes>>;
+
+/// A decoded file on its way to the embedder.
+struct PendingMedia {
+    media_id: Uuid,
+    details: MediaDetails,
+    /// How the file was resolved if its content is already stored and only
+    /// lacks an embedding from the current model
+    stored_as: Option<IngestOutcome>,
+}
+
+/// Ingest a list of files through a three stage pipeline.
+///
+/// Images are decoded on a pool of blocking threads, embedded and
+/// thumbnailed in batches of `options.batch_size`, and written to the
+/// database one batch per transaction. The stages are joined by bounded
+/// channels holding about `options.channel_capacity` images each, so a slow
+/// stage applies back-pressure instead of buffering the whole library in
+/// memory. Decoded pixels are dropped as soon as a batch is embedded and
+/// thumbnailed.
+///
+/// New content is copied or moved into the library according to
+/// `options.import_mode` just before it is written.
+///
+/// `on_result` is called once for every input file, in completion order.
+/// Failures are recorded in `ingest_failures` as they happen so they can be
+/// retried even if the run is interrupted, and files that now succeed are
+/// cleared from it.
+///
+/// Once `cancelled` is set no further files are started; files already in
+/// flight are finished and reported, the rest are not.
+pub async fn run<F>(
+    files: Vec<PathBuf>,
+    state: &AppState,
+    options: &IngestConfig,
+    cancelled: Arc<AtomicBool>,
+    mut on_result: F,
+) -> Result<IngestSummary>
+where
+    F: FnMut(&FileResult),
+{
+    let capacity = options.channel_capacity.max(1);
+    let batch_size = options.batch_size.max(1);
+    let (decoded_tx, decoded_rx) = mpsc::channel::<MediaDetails>(capacity);
+    // Embedded batches no longer hold their images, but keep the number of
+    // files buffered between the stages in line with `channel_capacity`
+    let (embedded_tx, embedded_rx) =
+        mpsc::channel::<Vec<EmbeddedMedia>>((capacity / batch_size).max(1));
+    let (result_tx, mut result_rx) = mpsc::unbounded_channel::<FileResult>();
+
+    let queue = Arc::new(Mutex::new(files.into_iter()));
+    let mut decode_handles = Vec::with_capacity(options.decode_workers);
+    for _ in 0..options.decode_workers.max(1) {
+        let queue = Arc::clone(&queue);
+        let cancelled = Arc::clone(&cancelled);
+        let decoded_tx = decoded_tx.clone();
+        let result_tx = result_tx.clone();
+        decode_handles.push(tokio::task::spawn_blocking(move || {
+            decode_worker(queue, cancelled, decoded_tx, result_tx)
+        }));
+    }
+    // The embed stage stops once every decode worker has dropped its sender
+    drop(decoded_tx);
+
+    let hashes = SharedHashes::default();
+    let embed_handle = tokio::spawn(embed_stage(
+        decoded_rx,
+        embedded_tx,
+        result_tx.clone(),
+        Arc::clone(&hashes),
+        state.db_pool.clone(),
+        Arc::clone(&state.embedder),
+        Arc::new(state.config.storage.clone()),
+        options.import_mode,
+        batch_size,
+    ));
+    let store_handle = tokio::spawn(store_stage(
+        embedded_rx,
+        result_tx,
+        hashes,
+        state.db_pool.clone(),
+        state.embedder.model_id().to_string(),
+        options.import_mode,
+    ));
+
+    let (record_tx, record_rx) = mpsc::unbounded_channel();
+    let recorder = tokio::spawn(record_failures(state.db_pool.clone(), record_rx));
+
+    let mut summary = IngestSummary::default();
+    while let Some(result) = result_rx.recv().await {
+        summary.record(&result);
+        on_result(&result);
+        let _ = record_tx.send(result);
+    }
+
+    drop(record_tx);
+    recorder.await?;
+    for handle in decode_handles {
+        handle.await?;
+    }
+    embed_handle.await?;
+    store_handle.await?;
+
+    Ok(summary)
+}
+
+/// Write failures to `ingest_failures`, and clear files that succeeded, in
+/// batches as results arrive.
+async fn record_failures(pool: PgPool, mut results: mpsc::UnboundedReceiver<FileResult>) {
+    let mut batch = Vec::with_capacity(FAILURE_BATCH_SIZE);
+
+    while results.recv_many(&mut batch, FAILURE_BATCH_SIZE).await > 0 {
+        let mut failed = Vec::new();
+        let mut succeeded = Vec::new();
+        for result in batch.drain(..) {
+            match result {
+                FileResult::Ingested { path, .. } => succeeded.push(path),
+                FileResult::Failed { path, stage, error } => failed.push((path, stage, error)),
+            }
+        }
+
+        // The files themselves were processed, so losing the report is only
+        // worth a warning
+        if let Err(e) = failures::record_results(&pool, &failed, &succeeded).await {
+            warn!("Failed to record ingest failures: {:#}", e);
+        }
+    }
+}
+
+fn decode_worker(
+    queue: Arc<Mutex<std::vec::IntoIter<PathBuf>>>,
+    cancelled: Arc<AtomicBool>,
+    decoded_tx: mpsc::Sender<MediaDetails>,
+    result_tx: ResultSender,
+) {
+    loop {
+        if cancelled.load(Ordering::Relaxed) {
+            break;
+        }
+
+        let next = queue.lock().unwrap().next();
+        let Some(path) = next else {
+            break;
+        };
+
+        match extract_media_details_from_path(&path) {
+            Ok(details) => {
+                if decoded_tx.blocking_send(details).is_err() {
+                    // Downstream has shut down, nothing left to do
+                    break;
+                }
+            }
+            Err(e) => {
+                let _ = result_tx.send(FileResult::Failed {
+                    path,
+                    stage: FailureStage::Decode,
+                    error: format!("Error extracting details: {}", e),
+                });
+            }
+        }
+    }
+}
+
+/// Receive up to `batch_size` items, waiting until the batch is full or the
+/// channel is closed. Returns `None` once the channel is drained.
+async fn next_batch<T>(rx: &mut mpsc::Receiver<T>, batch_size: usize) -> Option<Vec<T>> {
+    let mut batch = Vec::with_capacity(batch_size);
+    while batch.len() < batch_size {
+        if rx.recv_many(&mut batch, batch_size - batch.len()).await == 0 {
+            break;
+        }
+    }
+
+    if batch.is_empty() {
+        None
+    } else {
+        Some(batch)
+    }
+}
+
+async fn embed_stage(
+    mut decoded_rx: mpsc::Receiver<MediaDetails>,
+    embedded_tx: mpsc::Sender<Vec<EmbeddedMedia>>,
+    result_tx: ResultSender,
+    hashes: SharedHashes,
+    pool: PgPool,
+    embedder: Arc<dyn Embedder>,
+    storage: Arc<StorageConfig>,
+    import_mode: ImportMode,
+    batch_size: usize,
+) {
+    while let Some(batch) = next_batch(&mut decoded_rx, batch_size).await {
+        let pending = dedupe_batch(&pool, batch, &hashes, embedder.model_id(), &result_tx).await;
+        if pending.is_empty() {
+            continue;
+        }
+
+        let claimed: Vec<(String, PathBuf)> = pending
+            .iter()
+            .map(|item| {
+                (
+                    item.details.content_hash.clone(),
+                    PathBuf::from(&item.details.file_path),
+                )
+            })
+            .collect();
+        let batch_len = pending.len();
+
+        let embedder = Arc::clone(&embedder);
+        let storage = Arc::clone(&storage);
+        let import_hashes = Arc::clone(&hashes);
+        let import_result_tx = result_tx.clone();
+        let start = std::time::Instant::now();
+        let embedded = tokio::task::spawn_blocking(move || {
+            let batch = embed_batch(&embedder, &storage, pending)?;
+            Ok::<_, anyhow::Error>(import_batch(
+                batch,
+                import_mode,
+                &storage,
+                &import_hashes,
+                &import_result_tx,
+            ))
+        })
+        .await;
+
+        match embedded {
+            Ok(Ok(batch)) => {
+                debug!(
+                    "Embedded batch of {} images in {:?}",
+                    batch_len,
+                    start.elapsed()
+                );
+                if batch.is_empty() {
+                    continue;
+                }
+                if embedded_tx.send(batch).await.is_err() {
+                    break;
+                }
+            }
+            Ok(Err(e)) => hashes.lock().unwrap().fail_all(
+                claimed,
+                FailureStage::Embed,
+                &format!("Error embedding: {}", e),
+                &result_tx,
+            ),
+            Err(e) => hashes.lock().unwrap().fail_all(
+                claimed,
+                FailureStage::Embed,
+                &format!("Embedding task failed: {}", e),
+                &result_tx,
+            ),
+        }
+    }
+}
+
+/// Split a decoded batch into files that need embedding and files whose
+/// content is already stored. Results for the latter are reported directly,
+/// and copies of content still on its way to the database are reported
+/// along with it. Stored content that `model_name` hasn't embedded is passed
+/// on to be embedded.
+async fn dedupe_batch(
+    pool: &PgPool,
+    batch: Vec<MediaDetails>,
+    hashes: &Mutex<RunHashes>,
+    model_name: &str,
+    result_tx: &ResultSender,
+) -> Vec<PendingMedia> {
+    let content_hashes: Vec<String> = batch.iter().map(|d| d.content_hash.clone()).collect();
+    let existing = match find_existing_by_hash(pool, &content_hashes, model_name).await {
+        Ok(existing) => existing,
+        Err(e) => {
+            let paths = batch.iter().map(|d| PathBuf::from(&d.file_path)).collect();
+            fail_all(
+                result_tx,
+                paths,
+                FailureStage::Db,
+                &format!("Error checking for duplicates: {}", e),
+            );
+            return Vec::new();
+        }
+    };
+
+    let mut pending = Vec::with_capacity(batch.len());
+    for details in batch {
+        let path = PathBuf::from(&details.file_path);
+
+        let (media_id, stored_as) = match existing.get(&details.content_hash) {
+            Some(existing) => {
+                let outcome = match resolve_duplicate(pool, existing, &details).await {
+                    Ok(outcome) => outcome,
+                    Err(e) => {
+                        let _ = result_tx.send(FileResult::Failed {
+                            path,
+                            stage: FailureStage::Db,
+                            error: format!("Error updating duplicate: {}", e),
+                        });
+                        continue;
+                    }
+                };
+                if existing.embedded {
+                    let _ = result_tx.send(FileResult::Ingested { path, outcome });
+                    continue;
+                }
+                (existing.id, Some(outcome))
+            }
+            None => (Uuid::new_v4(), None),
+        };
+
+        let claim = hashes.lock().unwrap().claim(&details.content_hash, &path);
+        match claim {
+            Claim::Stored(media_id) => {
+                let _ = result_tx.send(FileResult::Ingested {
+                    path,
+                    outcome: stored_as.unwrap_or(IngestOutcome::Skipped(media_id)),
+                });
+            }
+            Claim::InFlight => {}
+            Claim::New => pending.push(PendingMedia {
+                media_id,
+                details,
+                stored_as,
+            }),
+        }
+    }
+
+    pending
+}
+
+fn embed_batch(
+    embedder: &dyn Embedder,
+    storage: &StorageConfig,
+    mut pending: Vec<PendingMedia>,
+) -> Result<Vec<EmbeddedMedia>> {
+    // Move the decoded images out so the batch can be stacked without copying
+    let images: Vec<DynamicImage> = pending
+        .iter_mut()
+        .map(|item| std::mem::take(&mut item.details.image))
+        .collect();
+    let embeddings = embedder.encode_images(&images)?.to_vec2::<f32>()?;
+
+    Ok(pending
+        .into_iter()
+        .zip(images)
+        .zip(embeddings)
+        .map(|((mut item, image), embedding)| {
+            item.details.image = image;
+            // Stored content already has its thumbnails
+            let thumbnails = match item.stored_as {
+                Some(_) => Vec::new(),
+                None => thumbnails_for(&item.details, storage),
+            };
+            let mut embedded =
+                EmbeddedMedia::new(item.media_id, item.details, embedding, thumbnails);
+            embedded.stored_as = item.stored_as;
+            embedded
+        })
+        .collect())
+}
+
+/// Copy each new item of an embedded batch into the library. Items that fail
+/// to import are reported and dropped from the batch.
+fn import_batch(
+    batch: Vec<EmbeddedMedia>,
+    import_mode: ImportMode,
+    storage: &StorageConfig,
+    hashes: &Mutex<RunHashes>,
+    result_tx: &ResultSender,
+) -> Vec<EmbeddedMedia> {
+    batch
+        .into_iter()
+        .filter_map(|mut item| {
+            if item.stored_as.is_some() {
+                return Some(item);
+            }
+
+            match prepare_import(&mut item.details, import_mode, storage) {
+                Ok(imported_from) => {
+                    item.imported_from = imported_from;
+                    Some(item)
+                }
+                Err(e) => {
+                    let result = FileResult::Failed {
+                        path: item.source_path(),
+                        stage: FailureStage::Import,
+                        error: format!("Error importing: {:#}", e),
+                    };
+                    hashes
+                        .lock()
+                        .unwrap()
+                        .settle(&item.details.content_hash, result, result_tx);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+async fn store_stage(
+    mut embedded_rx: mpsc::Receiver<Vec<EmbeddedMedia>>,
+    result_tx: ResultSender,
+    hashes: SharedHashes,
+    pool: PgPool,
+    model_name: String,
+    import_mode: ImportMode,
+) {
+    while let Some(batch) = embedded_rx.recv().await {
+        let stored = insert_media_batch(&pool, &model_name, &batch).await;
+        finish_import(&batch, import_mode, stored.is_ok());
+
+        let mut hashes = hashes.lock().unwrap();
+        match stored {
+            Ok(()) => {
+                for item in batch {
+                    let result = FileResult::Ingested {
+                        path: item.source_path(),
+                        outcome: item.outcome(),
+                    };
+                    hashes.settle(&item.details.content_hash, result, &result_tx);
+                }
+            }
+            Err(e) => {
+                let claimed = batch
+                    .iter()
+                    .map(|item| (item.details.content_hash.clone(), item.source_path()))
+                    .collect();
+                hashes.fail_all(
+                    claimed,
+                    FailureStage::Db,
+                    &format!("Error saving to database: {}", e),
+                    &result_tx,
+                );
+            }
+        }
+    }
+}
+
+fn fail_all(result_tx: &ResultSender, paths: Vec<PathBuf>, stage: FailureStage, error: &str) {
+    for path in paths {
+        let _ = result_tx.send(FileResult::Failed {
+            path,
+            stage,
+            error: error.to_string(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain(result_rx: &mut mpsc::UnboundedReceiver<FileResult>) -> Vec<FileResult> {
+        std::iter::from_fn(|| result_rx.try_recv().ok()).collect()
+    }
+
+    #[test]
+    fn test_copies_share_the_failure_of_their_batch() {
+        let (result_tx, mut result_rx) = mpsc::unbounded_channel();
+        let mut hashes = RunHashes::default();
+
+        assert_eq!(hashes.claim("red", Path::new("/a/red.png")), Claim::New);
+        assert_eq!(
+            hashes.claim("red", Path::new("/a/red-copy.png")),
+            Claim::InFlight
+        );
+
+        hashes.fail_all(
+            vec![("red".to_string(), PathBuf::from("/a/red.png"))],
+            FailureStage::Embed,
+            "out of memory",
+            &result_tx,
+        );
+        let results = drain(&mut result_rx);
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(|result| matches!(
+            result,
+            FileResult::Failed { stage: FailureStage::Embed, error, .. } if error == "out of memory"
+        )));
+
+        // The content was never stored, so a later copy is ingested afresh
+        assert_eq!(hashes.claim("red", Path::new("/b/red.png")), Claim::New);
+    }
+
+    #[test]
+    fn test_copies_are_skipped_once_their_batch_is_stored() {
+        let (result_tx, mut result_rx) = mpsc::unbounded_channel();
+        let mut hashes = RunHashes::default();
+        let media_id = Uuid::new_v4();
+
+        assert_eq!(hashes.claim("red", Path::new("/a/red.png")), Claim::New);
+        assert_eq!(
+            hashes.claim("red", Path::new("/a/red-copy.png")),
+            Claim::InFlight
+        );
+
+        let stored = FileResult::Ingested {
+            path: PathBuf::from("/a/red.png"),
+            outcome: IngestOutcome::New(media_id),
+        };
+        hashes.settle("red", stored, &result_tx);
+        let results = drain(&mut result_rx);
+        assert!(matches!(
+            results.as_slice(),
+            [
+                FileResult::Ingested { outcome: IngestOutcome::New(_), .. },
+                FileResult::Ingested { outcome: IngestOutcome::Skipped(id), .. },
+            ] if *id == media_id
+        ));
+
+        assert_eq!(
+            hashes.claim("red", Path::new("/b/red.png")),
+            Claim::Stored(media_id)
+        );
+    }
+}
@@ -0,0 +1,379 @@
+use crate::core::config::PreprocessingConfig;
+use anyhow::{bail, Context, Result};
+use image::imageops::FilterType;
+use image::{DynamicImage, RgbImage};
+use serde::Deserialize;
+use std::path::Path;
+use tracing::warn;
+
+/// Per-channel mean OpenAI CLIP was trained with
+const CLIP_MEAN: [f32; 3] = [0.48145466, 0.4578275, 0.40821073];
+
+/// Per-channel standard deviation OpenAI CLIP was trained with
+const CLIP_STD: [f32; 3] = [0.26862954, 0.26130258, 0.27577711];
+
+/// SigLIP's per-channel mean and standard deviation, mapping pixels to
+/// `[-1, 1]`
+const SIGLIP_MEAN_STD: [f32; 3] = [0.5, 0.5, 0.5];
+
+/// How an image is resized before the centre crop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResizeMode {
+    /// Resize the shortest edge, keeping the aspect ratio, as CLIP does
+    ShortestEdge,
+    /// Squash the image into a square, as SigLIP does
+    Exact,
+}
+
+/// How images are turned into model input, mirroring the Hugging Face
+/// `CLIPImageProcessor` and `SiglipImageProcessor`: resize, crop the centre
+/// square, then scale to `[0, 1]` and normalize each channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImagePreprocessing {
+    pub resize: ResizeMode,
+    /// Length the shortest edge is resized to, or the side of the square
+    /// with [`ResizeMode::Exact`]
+    pub shortest_edge: u32,
+    /// Side of the square cut from the centre of the resized image
+    pub crop_size: u32,
+    pub filter: FilterType,
+    pub mean: [f32; 3],
+    pub std: [f32; 3],
+}
+
+impl Default for ImagePreprocessing {
+    /// The preprocessing of OpenAI's released CLIP models
+    fn default() -> Self {
+        Self {
+            resize: ResizeMode::ShortestEdge,
+            shortest_edge: 224,
+            crop_size: 224,
+            filter: FilterType::CatmullRom,
+            mean: CLIP_MEAN,
+            std: CLIP_STD,
+        }
+    }
+}
+
+/// The fields of a `preprocessor_config.json` that affect image input.
+#[derive(Debug, Deserialize)]
+struct PreprocessorConfig {
+    size: Option<SizeSpec>,
+    do_center_crop: Option<bool>,
+    crop_size: Option<SizeSpec>,
+    resample: Option<u8>,
+    image_mean: Option<[f32; 3]>,
+    image_std: Option<[f32; 3]>,
+}
+
+/// Sizes are a bare number in older configs and an object in newer ones.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum SizeSpec {
+    Edge(u32),
+    ShortestEdge { shortest_edge: u32 },
+    Square { height: u32, width: u32 },
+}
+
+impl SizeSpec {
+    fn edge(&self) -> Result<u32> {
+        match *self {
+            SizeSpec::Edge(edge)
+            | SizeSpec::ShortestEdge {
+                shortest_edge: edge,
+            } => Ok(edge),
+            SizeSpec::Square { height, width } if height == width => Ok(height),
+            SizeSpec::Square { height, width } => {
+                bail!(
+                    "Non-square image size {}x{} is not supported",
+                    width,
+                    height
+                )
+            }
+        }
+    }
+}
+
+/// Map a PIL resampling filter id to the closest `image` filter.
+fn filter_from_pil(resample: u8) -> Result<FilterType> {
+    Ok(match resample {
+        0 => FilterType::Nearest,
+        1 => FilterType::Lanczos3,
+        2 => FilterType::Triangle,
+        3 => FilterType::CatmullRom,
+        other => bail!("Unsupported resampling filter {}", other),
+    })
+}
+
+impl ImagePreprocessing {
+    /// The preprocessing of Google's SigLIP models: a bicubic resize straight
+    /// to 224x224 and normalization to `[-1, 1]`
+    pub fn siglip() -> Self {
+        Self {
+            resize: ResizeMode::Exact,
+            mean: SIGLIP_MEAN_STD,
+            std: SIGLIP_MEAN_STD,
+            ..Self::default()
+        }
+    }
+
+    /// Parse a Hugging Face `preprocessor_config.json`. Missing fields keep
+    /// the values of `defaults`.
+    ///
+    /// A `size` given as height and width is resized to exactly, and the
+    /// centre crop is skipped when `do_center_crop` is false or no
+    /// `crop_size` is given.
+    pub fn from_preprocessor_config(json: &str, defaults: Self) -> Result<Self> {
+        let config: PreprocessorConfig = serde_json::from_str(json)?;
+
+        let (resize, shortest_edge) = match &config.size {
+            Some(size @ SizeSpec::Square { .. }) => (ResizeMode::Exact, size.edge()?),
+            Some(size) => (ResizeMode::ShortestEdge, size.edge()?),
+            None => (defaults.resize, defaults.shortest_edge),
+        };
+        let crop_size = match (&config.crop_size, config.do_center_crop) {
+            (Some(size), None | Some(true)) => size.edge()?,
+            _ => shortest_edge,
+        };
+
+        Ok(Self {
+            resize,
+            shortest_edge,
+            crop_size,
+            filter: match config.resample {
+                Some(resample) => filter_from_pil(resample)?,
+                None => defaults.filter,
+            },
+            mean: config.image_mean.unwrap_or(defaults.mean),
+            std: config.image_std.unwrap_or(defaults.std),
+        })
+    }
+
+    /// Read the model's preprocessor config if it has one, falling back to
+    /// `defaults`, then apply the overrides from `embedding.preprocessing`.
+    pub fn load(
+        path: Option<&Path>,
+        defaults: Self,
+        overrides: &PreprocessingConfig,
+    ) -> Result<Self> {
+        let preprocessing = match path {
+            Some(path) => {
+                let json = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read {:?}", path))?;
+                Self::from_preprocessor_config(&json, defaults)
+                    .with_context(|| format!("Invalid preprocessor config {:?}", path))?
+            }
+            None => {
+                warn!("Model has no preprocessor_config.json, using its default preprocessing");
+                defaults
+            }
+        };
+
+        Ok(preprocessing.with_overrides(overrides))
+    }
+
+    pub fn with_overrides(self, overrides: &PreprocessingConfig) -> Self {
+        Self {
+            resize: self.resize,
+            shortest_edge: overrides.shortest_edge.unwrap_or(self.shortest_edge),
+            crop_size: overrides.crop_size.unwrap_or(self.crop_size),
+            filter: self.filter,
+            mean: overrides.image_mean.unwrap_or(self.mean),
+            std: overrides.image_std.unwrap_or(self.std),
+        }
+    }
+
+    /// Resize the image and cut out the centre square.
+    ///
+    /// When resizing the shortest edge, the long edge is truncated like
+    /// Python's `int()` does in `transformers`, so the crop lands on the same
+    /// pixels.
+    pub fn resize_and_crop(&self, image: &DynamicImage) -> RgbImage {
+        let (width, height) = (image.width().max(1), image.height().max(1));
+        let short = width.min(height) as u64;
+        let scale = |edge: u32| match self.resize {
+            ResizeMode::ShortestEdge => (self.shortest_edge as u64 * edge as u64 / short) as u32,
+            ResizeMode::Exact => self.shortest_edge,
+        };
+        let (resized_width, resized_height) = (
+            scale(width).max(self.crop_size),
+            scale(height).max(self.crop_size),
+        );
+
+        let resized = image.resize_exact(resized_width, resized_height, self.filter);
+        resized
+            .crop_imm(
+                (resized_width - self.crop_size) / 2,
+                (resized_height - self.crop_size) / 2,
+                self.crop_size,
+                self.crop_size,
+            )
+            .to_rgb8()
+    }
+
+    /// Normalize an 8-bit channel value.
+    pub fn normalize(&self, channel: usize, value: u8) -> f32 {
+        (value as f32 / 255. - self.mean[channel]) / self.std[channel]
+    }
+
+    /// The model input for an image: resized, cropped and normalized, laid
+    /// out channel first as `(3, crop_size, crop_size)`.
+    pub fn pixel_values(&self, image: &DynamicImage) -> Vec<f32> {
+        let pixels = self.resize_and_crop(image);
+        let size = self.crop_size as usize;
+
+        let mut values = vec![0f32; 3 * size * size];
+        for (x, y, pixel) in pixels.enumerate_pixels() {
+            for channel in 0..3 {
+                values[(channel * size + y as usize) * size + x as usize] =
+                    self.normalize(channel, pixel[channel]);
+            }
+        }
+        values
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    #[test]
+    fn test_preprocessor_config_formats() -> Result<()> {
+        let legacy = r#"{
+            "crop_size": 224,
+            "do_center_crop": true,
+            "do_normalize": true,
+            "image_mean": [0.48145466, 0.4578275, 0.40821073],
+            "image_std": [0.26862954, 0.26130258, 0.27577711],
+            "resample": 3,
+            "size": 224
+        }"#;
+        assert_eq!(
+            ImagePreprocessing::from_preprocessor_config(legacy, ImagePreprocessing::default())?,
+            ImagePreprocessing::default()
+        );
+
+        let current = r#"{
+            "crop_size": {"height": 336, "width": 336},
+            "size": {"shortest_edge": 336},
+            "resample": 2,
+            "image_mean": [0.5, 0.5, 0.5],
+            "image_std": [0.5, 0.5, 0.5]
+        }"#;
+        let preprocessing =
+            ImagePreprocessing::from_preprocessor_config(current, ImagePreprocessing::default())?;
+        assert_eq!(preprocessing.resize, ResizeMode::ShortestEdge);
+        assert_eq!(preprocessing.shortest_edge, 336);
+        assert_eq!(preprocessing.crop_size, 336);
+        assert_eq!(preprocessing.filter, FilterType::Triangle);
+        assert_eq!(preprocessing.mean, [0.5; 3]);
+
+        let overridden = preprocessing.with_overrides(&PreprocessingConfig {
+            image_std: Some([0.25; 3]),
+            ..PreprocessingConfig::default()
+        });
+        assert_eq!(overridden.std, [0.25; 3]);
+        assert_eq!(overridden.mean, [0.5; 3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_siglip_preprocessor_config() -> Result<()> {
+        // google/siglip-base-patch16-224
+        let siglip = r#"{
+            "do_normalize": true,
+            "do_rescale": true,
+            "do_resize": true,
+            "image_mean": [0.5, 0.5, 0.5],
+            "image_processor_type": "SiglipImageProcessor",
+            "image_std": [0.5, 0.5, 0.5],
+            "resample": 3,
+            "rescale_factor": 0.00392156862745098,
+            "size": {"height": 224, "width": 224}
+        }"#;
+        assert_eq!(
+            ImagePreprocessing::from_preprocessor_config(siglip, ImagePreprocessing::siglip())?,
+            ImagePreprocessing::siglip()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_exact_resize_keeps_the_whole_image() {
+        let image = RgbImage::from_fn(300, 100, |x, _| match x / 100 {
+            0 => Rgb([255, 0, 0]),
+            1 => Rgb([0, 255, 0]),
+            _ => Rgb([0, 0, 255]),
+        });
+
+        let squashed =
+            ImagePreprocessing::siglip().resize_and_crop(&DynamicImage::ImageRgb8(image));
+        assert_eq!(squashed.dimensions(), (224, 224));
+        assert_eq!(squashed.get_pixel(10, 112), &Rgb([255, 0, 0]));
+        assert_eq!(squashed.get_pixel(213, 112), &Rgb([0, 0, 255]));
+    }
+
+    #[test]
+    fn test_resize_and_crop_keeps_the_centre() {
+        // Red, green and blue thirds: only green should survive the crop
+        let image = RgbImage::from_fn(300, 100, |x, _| match x / 100 {
+            0 => Rgb([255, 0, 0]),
+            1 => Rgb([0, 255, 0]),
+            _ => Rgb([0, 0, 255]),
+        });
+
+        let cropped =
+            ImagePreprocessing::default().resize_and_crop(&DynamicImage::ImageRgb8(image));
+        assert_eq!(cropped.dimensions(), (224, 224));
+        assert_eq!(cropped.get_pixel(112, 112), &Rgb([0, 255, 0]));
+    }
+
+    /// The 64x48 gradient of `fixture_image` in embedding.rs
+    fn gradient() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(64, 48, |x, y| {
+            Rgb([(x * 4) as u8, (y * 5) as u8, ((x + y) * 2) as u8])
+        }))
+    }
+
+    #[test]
+    fn test_pixel_values_match_transformers() {
+        // transformers resizes the gradient to 298x224 with a bicubic filter
+        // and crops 37 columns off the left. Bicubic resampling reproduces
+        // linear gradients exactly away from the image edges, so the values
+        // it feeds the model follow from the source position of each pixel,
+        // up to rounding to 8 bits.
+        let size = 224;
+        let values = ImagePreprocessing::default().pixel_values(&gradient());
+        assert_eq!(values.len(), 3 * size * size);
+
+        for y in (16..=208).step_by(16) {
+            for x in (0..size).step_by(16) {
+                let u = ((x + 37) as f32 + 0.5) * 64. / 298. - 0.5;
+                let v = (y as f32 + 0.5) * 48. / 224. - 0.5;
+                let channels = [4. * u, 5. * v, 2. * (u + v)];
+
+                for (channel, value) in channels.into_iter().enumerate() {
+                    let expected = (value / 255. - CLIP_MEAN[channel]) / CLIP_STD[channel];
+                    let actual = values[(channel * size + y) * size + x];
+                    assert!(
+                        (actual - expected).abs() < 0.02,
+                        "channel {} at ({}, {}) is {}, expected {}",
+                        channel,
+                        x,
+                        y,
+                        actual,
+                        expected
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_normalize_uses_channel_statistics() {
+        let preprocessing = ImagePreprocessing::default();
+        assert!((preprocessing.normalize(0, 255) - (1. - CLIP_MEAN[0]) / CLIP_STD[0]).abs() < 1e-6);
+        assert!((preprocessing.normalize(2, 0) + CLIP_MEAN[2] / CLIP_STD[2]).abs() < 1e-6);
+    }
+}
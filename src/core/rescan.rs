@@ -0,0 +1,547 @@
+use crate::core::ingest::reembed_media;
+use crate::core::media::{extract_media_details_from_path, hash_bytes, images_under, modified_at};
+use crate::core::state::AppState;
+use crate::core::thumbnails::remove_thumbnail_files;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use sqlx::postgres::PgPool;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Size and modification time of a file on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStamp {
+    pub size: u64,
+    pub modified_at: Option<DateTime<Utc>>,
+}
+
+impl FileStamp {
+    /// Stamp of a regular file, or `None` if there isn't one at `path`.
+    pub fn of(path: &Path) -> Option<Self> {
+        let metadata = std::fs::metadata(path).ok()?;
+        metadata.is_file().then(|| FileStamp {
+            size: metadata.len(),
+            modified_at: modified_at(path),
+        })
+    }
+}
+
+/// What the database knows about a media item's file.
+#[derive(Debug, Clone)]
+pub struct MediaRecord {
+    pub id: Uuid,
+    pub file_path: String,
+    pub file_size: i64,
+    pub file_mtime: Option<DateTime<Utc>>,
+    pub content_hash: Option<String>,
+    /// The item holding this content, if this is an unhashed copy of it
+    pub duplicate_of: Option<Uuid>,
+    pub missing: bool,
+}
+
+/// A media item whose file is gone and wasn't found elsewhere.
+#[derive(Debug, Clone)]
+pub struct MissingFile {
+    pub media_id: Uuid,
+    pub file_path: String,
+    pub content_hash: Option<String>,
+}
+
+/// A media item whose content was found at a new path.
+#[derive(Debug, Clone)]
+pub struct MovedFile {
+    pub media_id: Uuid,
+    pub from: String,
+    pub to: PathBuf,
+    pub stamp: FileStamp,
+}
+
+/// A media item whose file still exists but holds different content.
+#[derive(Debug, Clone)]
+pub struct ChangedFile {
+    pub media_id: Uuid,
+    pub path: PathBuf,
+}
+
+/// A media item whose content is unchanged but whose stored stamp, hash or
+/// missing flag is out of date.
+#[derive(Debug, Clone)]
+pub struct StaleRecord {
+    pub media_id: Uuid,
+    pub path: PathBuf,
+    pub stamp: FileStamp,
+    pub content_hash: String,
+}
+
+/// A media item without a content hash whose content is held by another
+/// item, so it can't take the hash itself.
+#[derive(Debug, Clone)]
+pub struct DuplicateRecord {
+    pub media_id: Uuid,
+    pub path: PathBuf,
+    pub stamp: FileStamp,
+    pub duplicate_of: Uuid,
+}
+
+/// The differences between the library roots on disk and the database.
+#[derive(Debug, Default)]
+pub struct RescanReport {
+    /// Media whose file is where the database says, with the same content
+    pub unchanged: usize,
+    pub stale: Vec<StaleRecord>,
+    /// Unhashed media that are copies of another item
+    pub duplicate_records: Vec<DuplicateRecord>,
+    pub missing: Vec<MissingFile>,
+    pub moved: Vec<MovedFile>,
+    pub changed: Vec<ChangedFile>,
+    /// Files under a root whose content isn't in the library
+    pub new: Vec<PathBuf>,
+    /// Files under a root that are copies of content stored elsewhere
+    pub duplicates: Vec<PathBuf>,
+    /// Files that couldn't be read while comparing
+    pub errors: Vec<(PathBuf, String)>,
+}
+
+/// Options for applying a [`RescanReport`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RescanOptions {
+    /// Delete rows of missing media instead of flagging them
+    pub prune: bool,
+    /// Re-embed media whose file content changed
+    pub reembed: bool,
+}
+
+/// What applying a report changed.
+#[derive(Debug, Default)]
+pub struct RescanSummary {
+    pub refreshed: usize,
+    pub marked_duplicate: usize,
+    pub relocated: usize,
+    pub marked_missing: u64,
+    pub pruned: u64,
+    pub reembedded: usize,
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+/// The directories a rescan walks: `storage.library_roots` plus
+/// `storage.media_path`.
+pub fn library_roots(state: &AppState) -> Vec<PathBuf> {
+    let storage = &state.config.storage;
+    let mut roots = storage.library_roots.clone();
+    if !roots.contains(&storage.media_path) {
+        roots.push(storage.media_path.clone());
+    }
+    roots
+}
+
+/// Compare the library roots on disk against the database without changing
+/// anything.
+pub async fn scan(state: &AppState) -> Result<RescanReport> {
+    let records = sqlx::query_as!(
+        MediaRecord,
+        r#"
+        SELECT id, file_path, file_size, file_mtime, content_hash, duplicate_of,
+               missing_at IS NOT NULL as "missing!"
+        FROM media
+        ORDER BY file_path
+        "#
+    )
+    .fetch_all(&state.db_pool)
+    .await?;
+
+    let roots = library_roots(state);
+    let exclude = vec![state.config.storage.media_path.join("thumbnails")];
+
+    let report = tokio::task::spawn_blocking(move || {
+        let disk = roots
+            .iter()
+            .flat_map(|root| images_under(root, &exclude))
+            .filter_map(|path| FileStamp::of(&path).map(|stamp| (path, stamp)))
+            .collect();
+        diff(records, disk, FileStamp::of, hash_file)
+    })
+    .await?;
+
+    Ok(report)
+}
+
+fn hash_file(path: &Path) -> Result<String, String> {
+    std::fs::read(path)
+        .map(|bytes| hash_bytes(&bytes))
+        .map_err(|e| e.to_string())
+}
+
+/// Diff database records against files found on disk.
+///
+/// A record whose size and mtime match its file is trusted without reading
+/// it; otherwise the file is hashed. Files under the roots that no record
+/// points at are hashed so content that merely moved can be told apart from
+/// new content. Records without a hash whose content another record holds
+/// are reported as duplicates of it, since the hash is unique.
+fn diff(
+    records: Vec<MediaRecord>,
+    mut disk: HashMap<PathBuf, FileStamp>,
+    stat: impl Fn(&Path) -> Option<FileStamp>,
+    hash: impl Fn(&Path) -> Result<String, String>,
+) -> RescanReport {
+    let mut report = RescanReport::default();
+    let mut missing_by_hash: HashMap<String, MissingFile> = HashMap::new();
+    let owners: HashMap<String, Uuid> = records
+        .iter()
+        .filter_map(|record| Some((record.content_hash.clone()?, record.id)))
+        .collect();
+
+    for record in records {
+        let path = PathBuf::from(&record.file_path);
+        // Files outside the roots are still checked where they are
+        let Some(stamp) = disk.remove(&path).or_else(|| stat(&path)) else {
+            let missing = MissingFile {
+                media_id: record.id,
+                file_path: record.file_path,
+                content_hash: record.content_hash,
+            };
+            match missing.content_hash.clone() {
+                Some(content_hash) => {
+                    missing_by_hash.insert(content_hash, missing);
+                }
+                None => report.missing.push(missing),
+            }
+            continue;
+        };
+
+        let stamp_matches = record.file_size == stamp.size as i64
+            && record.file_mtime.is_some()
+            && record.file_mtime == stamp.modified_at;
+        let identified = record.content_hash.is_some() || record.duplicate_of.is_some();
+        if stamp_matches && identified && !record.missing {
+            report.unchanged += 1;
+            continue;
+        }
+
+        match hash(&path) {
+            Ok(content_hash) if record.content_hash.is_none() => {
+                report.unchanged += 1;
+                match owners.get(&content_hash) {
+                    Some(&duplicate_of) => report.duplicate_records.push(DuplicateRecord {
+                        media_id: record.id,
+                        path,
+                        stamp,
+                        duplicate_of,
+                    }),
+                    None => report.stale.push(StaleRecord {
+                        media_id: record.id,
+                        path,
+                        stamp,
+                        content_hash,
+                    }),
+                }
+            }
+            Ok(content_hash) if record.content_hash.as_ref() == Some(&content_hash) => {
+                report.unchanged += 1;
+                report.stale.push(StaleRecord {
+                    media_id: record.id,
+                    path,
+                    stamp,
+                    content_hash,
+                });
+            }
+            Ok(_) => report.changed.push(ChangedFile {
+                media_id: record.id,
+                path,
+            }),
+            Err(e) => report.errors.push((path, e)),
+        }
+    }
+
+    let mut unknown: Vec<(PathBuf, FileStamp)> = disk.into_iter().collect();
+    unknown.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    for (path, stamp) in unknown {
+        let content_hash = match hash(&path) {
+            Ok(content_hash) => content_hash,
+            Err(e) => {
+                report.errors.push((path, e));
+                continue;
+            }
+        };
+
+        if let Some(missing) = missing_by_hash.remove(&content_hash) {
+            report.moved.push(MovedFile {
+                media_id: missing.media_id,
+                from: missing.file_path,
+                to: path,
+                stamp,
+            });
+        } else if owners.contains_key(&content_hash) {
+            report.duplicates.push(path);
+        } else {
+            report.new.push(path);
+        }
+    }
+
+    report.missing.extend(missing_by_hash.into_values());
+    report.missing.sort_by(|a, b| a.file_path.cmp(&b.file_path));
+    report
+}
+
+/// Bring the database in line with a report.
+///
+/// Stale records are refreshed, unhashed copies are marked as duplicates,
+/// moved media are pointed at their new path and missing media are flagged,
+/// or deleted with `prune`. Changed files are only re-embedded with
+/// `reembed`. Rows that fail to update are collected in the summary rather
+/// than ending the rescan.
+pub async fn apply(
+    report: &RescanReport,
+    options: RescanOptions,
+    state: &AppState,
+) -> Result<RescanSummary> {
+    let pool = &state.db_pool;
+    let mut summary = RescanSummary::default();
+
+    for stale in &report.stale {
+        match refresh_stale(pool, stale).await {
+            Ok(true) => summary.refreshed += 1,
+            Ok(false) => summary.marked_duplicate += 1,
+            Err(e) => summary
+                .failed
+                .push((stale.path.clone(), format!("{:#}", e))),
+        }
+    }
+
+    for duplicate in &report.duplicate_records {
+        let marked = sqlx::query!(
+            r#"
+            UPDATE media
+            SET file_size = $2, file_mtime = $3, duplicate_of = $4, missing_at = NULL
+            WHERE id = $1
+            "#,
+            duplicate.media_id,
+            duplicate.stamp.size as i64,
+            duplicate.stamp.modified_at,
+            duplicate.duplicate_of
+        )
+        .execute(pool)
+        .await;
+        match marked {
+            Ok(_) => summary.marked_duplicate += 1,
+            Err(e) => summary.failed.push((duplicate.path.clone(), e.to_string())),
+        }
+    }
+
+    for moved in &report.moved {
+        let filename = moved
+            .to
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let relocated = sqlx::query!(
+            r#"
+            UPDATE media
+            SET file_path = $2, filename = $3, file_mtime = $4, missing_at = NULL
+            WHERE id = $1
+            "#,
+            moved.media_id,
+            moved.to.to_string_lossy().to_string(),
+            filename,
+            moved.stamp.modified_at
+        )
+        .execute(pool)
+        .await;
+        match relocated {
+            Ok(_) => summary.relocated += 1,
+            Err(e) => summary.failed.push((moved.to.clone(), e.to_string())),
+        }
+    }
+
+    let missing_ids: Vec<Uuid> = report.missing.iter().map(|m| m.media_id).collect();
+    if options.prune {
+        summary.pruned = sqlx::query!("DELETE FROM media WHERE id = ANY($1)", &missing_ids)
+            .execute(pool)
+            .await?
+            .rows_affected();
+
+        let storage = &state.config.storage;
+        for missing in &report.missing {
+            if let Some(content_hash) = &missing.content_hash {
+                remove_thumbnail_files(&storage.media_path, content_hash, &storage.thumbnail_sizes);
+            }
+        }
+    } else {
+        summary.marked_missing = sqlx::query!(
+            r#"
+            UPDATE media
+            SET missing_at = CURRENT_TIMESTAMP
+            WHERE id = ANY($1) AND missing_at IS NULL
+            "#,
+            &missing_ids
+        )
+        .execute(pool)
+        .await?
+        .rows_affected();
+    }
+
+    if options.reembed {
+        for changed in &report.changed {
+            match reembed_changed(changed, state).await {
+                Ok(()) => summary.reembedded += 1,
+                Err(e) => summary
+                    .failed
+                    .push((changed.path.clone(), format!("{:#}", e))),
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Store a stale record's stamp and hash. If another item took the hash since
+/// the scan, the record is marked as its duplicate instead and `false` is
+/// returned.
+async fn refresh_stale(pool: &PgPool, stale: &StaleRecord) -> Result<bool> {
+    let refreshed = sqlx::query!(
+        r#"
+        UPDATE media
+        SET file_size = $2, file_mtime = $3, content_hash = $4,
+            duplicate_of = NULL, missing_at = NULL
+        WHERE id = $1
+          AND NOT EXISTS (SELECT 1 FROM media WHERE content_hash = $4 AND id <> $1)
+        "#,
+        stale.media_id,
+        stale.stamp.size as i64,
+        stale.stamp.modified_at,
+        stale.content_hash
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    if refreshed > 0 {
+        return Ok(true);
+    }
+
+    sqlx::query!(
+        r#"
+        UPDATE media
+        SET file_size = $2, file_mtime = $3, missing_at = NULL,
+            duplicate_of = (SELECT id FROM media WHERE content_hash = $4)
+        WHERE id = $1
+        "#,
+        stale.media_id,
+        stale.stamp.size as i64,
+        stale.stamp.modified_at,
+        stale.content_hash
+    )
+    .execute(pool)
+    .await?;
+    Ok(false)
+}
+
+async fn reembed_changed(changed: &ChangedFile, state: &AppState) -> Result<()> {
+    let details = {
+        let path = changed.path.clone();
+        tokio::task::spawn_blocking(move || {
+            extract_media_details_from_path(&path)
+                .map_err(|e| anyhow!("Error extracting details: {}", e))
+        })
+        .await??
+    };
+
+    reembed_media(changed.media_id, details, state).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stamp(size: u64) -> FileStamp {
+        FileStamp {
+            size,
+            modified_at: DateTime::from_timestamp(1_700_000_000, 0),
+        }
+    }
+
+    fn record(path: &str, size: i64, hash: &str) -> MediaRecord {
+        MediaRecord {
+            id: Uuid::new_v4(),
+            file_path: path.to_string(),
+            file_size: size,
+            file_mtime: stamp(0).modified_at,
+            content_hash: Some(hash.to_string()),
+            duplicate_of: None,
+            missing: false,
+        }
+    }
+
+    fn hash_from_name(path: &Path) -> Result<String, String> {
+        // Test files are named after their content
+        Ok(path.file_stem().unwrap().to_string_lossy().to_string())
+    }
+
+    #[test]
+    fn test_diff_trusts_matching_stamps_and_detects_changes() {
+        let records = vec![record("/a/one.jpg", 1, "one"), record("/a/two.jpg", 1, "x")];
+        let disk = HashMap::from([
+            (PathBuf::from("/a/one.jpg"), stamp(1)),
+            (PathBuf::from("/a/two.jpg"), stamp(2)),
+        ]);
+
+        let report = diff(records, disk, |_| None, hash_from_name);
+        assert_eq!(report.unchanged, 1);
+        assert!(report.stale.is_empty());
+        assert_eq!(report.changed.len(), 1);
+        assert_eq!(report.changed[0].path, PathBuf::from("/a/two.jpg"));
+    }
+
+    #[test]
+    fn test_diff_classifies_moved_missing_and_new() {
+        let records = vec![
+            record("/old/moved.jpg", 1, "moved"),
+            record("/old/gone.jpg", 1, "gone"),
+        ];
+        let disk = HashMap::from([
+            (PathBuf::from("/new/moved.jpg"), stamp(1)),
+            (PathBuf::from("/new/fresh.jpg"), stamp(1)),
+        ]);
+
+        let report = diff(records, disk, |_| None, hash_from_name);
+        assert_eq!(report.moved.len(), 1);
+        assert_eq!(report.moved[0].to, PathBuf::from("/new/moved.jpg"));
+        assert_eq!(report.missing.len(), 1);
+        assert_eq!(report.missing[0].file_path, "/old/gone.jpg");
+        assert_eq!(report.new, vec![PathBuf::from("/new/fresh.jpg")]);
+    }
+
+    #[test]
+    fn test_diff_marks_unhashed_copies_as_duplicates() {
+        let original = record("/a/cat.jpg", 1, "cat");
+        let copy = MediaRecord {
+            content_hash: None,
+            ..record("/b/cat.jpg", 1, "")
+        };
+        let unhashed = MediaRecord {
+            content_hash: None,
+            ..record("/b/dog.jpg", 1, "")
+        };
+        let (original_id, copy_id) = (original.id, copy.id);
+        let disk = HashMap::from([
+            (PathBuf::from("/a/cat.jpg"), stamp(1)),
+            (PathBuf::from("/b/cat.jpg"), stamp(1)),
+            (PathBuf::from("/b/dog.jpg"), stamp(1)),
+        ]);
+
+        let report = diff(
+            vec![copy, original, unhashed],
+            disk,
+            |_| None,
+            hash_from_name,
+        );
+        assert_eq!(report.unchanged, 3);
+        assert_eq!(report.duplicate_records.len(), 1);
+        assert_eq!(report.duplicate_records[0].media_id, copy_id);
+        assert_eq!(report.duplicate_records[0].duplicate_of, original_id);
+        assert_eq!(report.stale.len(), 1);
+        assert_eq!(report.stale[0].content_hash, "dog");
+    }
+}
@@ -0,0 +1,594 @@
+use crate::core::embedding::TextFit;
+use crate::core::media::MediaDetails;
+use crate::core::state::AppState;
+use anyhow::Result;
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use sqlx::postgres::PgPool;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Instant;
+use thiserror::Error;
+use tracing::debug;
+use uuid::Uuid;
+
+/// Optional constraints applied on top of vector similarity.
+#[derive(Debug, Clone, Default)]
+pub struct SearchFilters {
+    /// Only return media carrying every one of these tags
+    pub tags: Vec<String>,
+    /// Only return media with this MIME type
+    pub content_type: Option<String>,
+}
+
+/// A ranked text search over the media library.
+#[derive(Debug, Clone)]
+pub struct SearchQuery {
+    pub text: String,
+    pub limit: i64,
+    pub offset: i64,
+    /// Drop hits whose cosine similarity falls below this threshold
+    pub min_similarity: Option<f64>,
+    pub filters: SearchFilters,
+}
+
+impl SearchQuery {
+    pub fn new(text: impl Into<String>) -> Self {
+        Self {
+            text: text.into(),
+            limit: 10,
+            offset: 0,
+            min_similarity: None,
+            filters: SearchFilters::default(),
+        }
+    }
+
+    /// Check the page before it is split between the tiers or sent to
+    /// Postgres.
+    pub fn check_page(&self) -> Result<(), InvalidPage> {
+        InvalidPage::check(self.limit, self.offset)
+    }
+}
+
+/// A page of results that can't be fetched.
+#[derive(Debug, Error)]
+#[error("Limit must be at least 1 and offset at least 0, got limit {limit} and offset {offset}")]
+pub struct InvalidPage {
+    pub limit: i64,
+    pub offset: i64,
+}
+
+impl InvalidPage {
+    pub fn check(limit: i64, offset: i64) -> Result<(), InvalidPage> {
+        if limit < 1 || offset < 0 {
+            return Err(InvalidPage { limit, offset });
+        }
+        Ok(())
+    }
+}
+
+/// How a hit was matched, in ranking order.
+///
+/// Media carrying a tag named in the query outrank everything else, then
+/// media with a tag partially matching a query term, and finally plain CLIP
+/// similarity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchTier {
+    ExactTag,
+    PartialTag,
+    Vector,
+}
+
+impl SearchTier {
+    fn from_rank(rank: i32) -> Self {
+        match rank {
+            0 => SearchTier::ExactTag,
+            1 => SearchTier::PartialTag,
+            _ => SearchTier::Vector,
+        }
+    }
+}
+
+impl std::fmt::Display for SearchTier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SearchTier::ExactTag => write!(f, "exact tag"),
+            SearchTier::PartialTag => write!(f, "partial tag"),
+            SearchTier::Vector => write!(f, "similarity"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchHit {
+    pub media_id: Uuid,
+    pub filename: String,
+    pub file_path: String,
+    pub content_type: String,
+    pub similarity: f64,
+    pub tier: SearchTier,
+    /// When the photo was taken, from EXIF
+    pub captured_at: Option<NaiveDateTime>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+}
+
+/// The media columns every kind of hit is built from.
+struct HitRow {
+    id: Uuid,
+    filename: String,
+    file_path: String,
+    content_type: String,
+    captured_at: Option<NaiveDateTime>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    similarity: f64,
+}
+
+impl HitRow {
+    fn into_hit(self, tier: SearchTier) -> SearchHit {
+        SearchHit {
+            media_id: self.id,
+            filename: self.filename,
+            file_path: self.file_path,
+            content_type: self.content_type,
+            similarity: self.similarity,
+            tier,
+            captured_at: self.captured_at,
+            latitude: self.latitude,
+            longitude: self.longitude,
+        }
+    }
+}
+
+/// The hits of a text search and how the query fit the text encoder.
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchResults {
+    pub hits: Vec<SearchHit>,
+    /// Whether the query was embedded whole, truncated or chunked
+    pub query_fit: TextFit,
+}
+
+/// Minimum length of a query term before it is used for partial tag matching,
+/// so short words like "a" or "on" don't match half the library.
+const MIN_PARTIAL_TERM_LEN: usize = 3;
+
+/// Longest run of query words matched against tag names. Tags are short
+/// labels, and bounding the run keeps the candidates linear in the query.
+const MAX_TAG_WORDS: usize = 4;
+
+/// Number of query words considered for tag matching. Longer queries still
+/// reach the text encoder whole.
+const MAX_QUERY_WORDS: usize = 32;
+
+/// Split a query into lowercase words, keeping at most [`MAX_QUERY_WORDS`].
+fn query_words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric() && c != '-' && c != '\'')
+        .filter(|word| !word.is_empty())
+        .take(MAX_QUERY_WORDS)
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+/// Every contiguous run of up to [`MAX_TAG_WORDS`] words in the query, so
+/// multi-word tags such as "uncle bob" can match exactly.
+fn exact_tag_candidates(words: &[String]) -> Vec<String> {
+    let mut candidates = Vec::new();
+    for start in 0..words.len() {
+        let last = (start + MAX_TAG_WORDS).min(words.len());
+        for end in start + 1..=last {
+            candidates.push(words[start..end].join(" "));
+        }
+    }
+    candidates
+}
+
+fn partial_tag_terms(words: &[String]) -> Vec<String> {
+    words
+        .iter()
+        .filter(|word| word.chars().count() >= MIN_PARTIAL_TERM_LEN)
+        .cloned()
+        .collect()
+}
+
+/// Run a tiered search over the media library.
+///
+/// Hits are ranked by [`SearchTier`] first and by CLIP similarity within a
+/// tier. Tag-matched hits are pinned above the similarity threshold, so
+/// `min_similarity` only prunes plain vector matches.
+///
+/// Queries longer than the text encoder's context are handled as set in
+/// `embedding.long_text`; the results report which way was taken.
+pub async fn search(query: &SearchQuery, state: &AppState) -> Result<SearchResults> {
+    query.check_page()?;
+
+    let encode_start = Instant::now();
+    // A forward pass takes long enough on the CPU to stall the runtime
+    let embedder = Arc::clone(&state.embedder);
+    let text = query.text.clone();
+    let (query_fit, embedding_vec) = tokio::task::spawn_blocking(move || -> Result<_> {
+        let (text_embedding, query_fit) = embedder.encode_text(&text)?;
+        Ok((query_fit, text_embedding.flatten_all()?.to_vec1::<f32>()?))
+    })
+    .await??;
+    debug!(
+        "Encoded query {:?} in {:?}",
+        query.text,
+        encode_start.elapsed()
+    );
+
+    let words = query_words(&query.text);
+    let exact_terms = exact_tag_candidates(&words);
+    let partial_terms = partial_tag_terms(&words);
+
+    let query_start = Instant::now();
+    let pool = &state.db_pool;
+    let filters = &query.filters;
+    // Embeddings from different models aren't comparable
+    let model_name = state.embedder.model_id();
+
+    // Tag tiers are looked up from the tags and ranked here, so the vector
+    // tier can stay a plain nearest neighbour query on the HNSW index
+    let tiers = tag_matches(pool, &exact_terms, &partial_terms).await?;
+    let tagged_ids: Vec<Uuid> = tiers.keys().copied().collect();
+
+    let mut tag_hits: Vec<SearchHit> = if tagged_ids.is_empty() {
+        Vec::new()
+    } else {
+        sqlx::query_as!(
+            HitRow,
+            r#"
+            SELECT m.id, m.filename, m.file_path, m.content_type,
+                   m.captured_at, m.latitude, m.longitude,
+                   1 - (e.embedding <=> $1::vector) as "similarity!"
+            FROM media m
+            JOIN embeddings e ON m.id = e.media_id
+            WHERE m.id = ANY($2)
+              AND e.model_name = $5
+              AND ($3::text IS NULL OR m.content_type = $3)
+              AND (
+                cardinality($4::text[]) = 0
+                OR m.id IN (
+                    SELECT mt.media_id
+                    FROM media_tags mt
+                    JOIN tags t ON t.id = mt.tag_id
+                    WHERE t.name = ANY($4)
+                    GROUP BY mt.media_id
+                    HAVING COUNT(DISTINCT t.id) = cardinality($4)
+                )
+              )
+            "#,
+            &embedding_vec as &[f32],
+            &tagged_ids,
+            filters.content_type,
+            &filters.tags,
+            model_name
+        )
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|row| {
+            let tier = tiers[&row.id];
+            row.into_hit(tier)
+        })
+        .collect()
+    };
+    tag_hits.sort_by(|a, b| {
+        a.tier
+            .cmp(&b.tier)
+            .then(b.similarity.total_cmp(&a.similarity))
+    });
+
+    let (tag_page, vector_limit, vector_offset) =
+        split_page(tag_hits.len(), query.limit, query.offset);
+    let mut hits: Vec<SearchHit> = tag_hits.drain(tag_page).collect();
+
+    if vector_limit > 0 {
+        let vector_hits = sqlx::query_as!(
+            HitRow,
+            r#"
+            SELECT m.id, m.filename, m.file_path, m.content_type,
+                   m.captured_at, m.latitude, m.longitude,
+                   1 - (e.embedding <=> $1::vector) as "similarity!"
+            FROM media m
+            JOIN embeddings e ON m.id = e.media_id
+            WHERE m.id <> ALL($2)
+              AND e.model_name = $8
+              AND ($3::text IS NULL OR m.content_type = $3)
+              AND (
+                cardinality($4::text[]) = 0
+                OR m.id IN (
+                    SELECT mt.media_id
+                    FROM media_tags mt
+                    JOIN tags t ON t.id = mt.tag_id
+                    WHERE t.name = ANY($4)
+                    GROUP BY mt.media_id
+                    HAVING COUNT(DISTINCT t.id) = cardinality($4)
+                )
+              )
+              AND ($5::float8 IS NULL OR 1 - (e.embedding <=> $1::vector) >= $5)
+            ORDER BY e.embedding <=> $1::vector
+            LIMIT $6 OFFSET $7
+            "#,
+            &embedding_vec as &[f32],
+            &tagged_ids,
+            filters.content_type,
+            &filters.tags,
+            query.min_similarity,
+            vector_limit,
+            vector_offset,
+            model_name
+        )
+        .fetch_all(pool)
+        .await?;
+        hits.extend(
+            vector_hits
+                .into_iter()
+                .map(|row| row.into_hit(SearchTier::Vector)),
+        );
+    }
+
+    debug!(
+        "Tiered search returned {} hits in {:?}",
+        hits.len(),
+        query_start.elapsed()
+    );
+
+    Ok(SearchResults { hits, query_fit })
+}
+
+/// Media carrying a tag that matches the query, with the best tier each
+/// reaches. Only the tags are scanned, so this stays cheap however large
+/// the library grows.
+async fn tag_matches(
+    pool: &PgPool,
+    exact_terms: &[String],
+    partial_terms: &[String],
+) -> Result<HashMap<Uuid, SearchTier>> {
+    if exact_terms.is_empty() && partial_terms.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let rows = sqlx::query!(
+        r#"
+        SELECT mt.media_id as "media_id!",
+               MIN(CASE WHEN lower(t.name) = ANY($1::text[]) THEN 0 ELSE 1 END) as "tier!"
+        FROM tags t
+        JOIN media_tags mt ON mt.tag_id = t.id
+        WHERE lower(t.name) = ANY($1::text[])
+           OR EXISTS (
+               SELECT 1
+               FROM unnest($2::text[]) term
+               WHERE strpos(lower(t.name), term) > 0
+                  OR (length(t.name) >= 3 AND strpos(term, lower(t.name)) > 0)
+           )
+        GROUP BY mt.media_id
+        "#,
+        exact_terms,
+        partial_terms
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.media_id, SearchTier::from_rank(row.tier)))
+        .collect())
+}
+
+/// Split a page of results between the tag hits, which rank first, and the
+/// vector hits after them.
+///
+/// Returns the range of tag hits on the page and the `LIMIT` and `OFFSET` of
+/// the vector query. The page must have passed [`InvalidPage::check`].
+fn split_page(tag_hits: usize, limit: i64, offset: i64) -> (Range<usize>, i64, i64) {
+    let tag_hits = tag_hits as i64;
+    let start = offset.min(tag_hits);
+    let end = (offset + limit).min(tag_hits);
+    (
+        start as usize..end as usize,
+        limit - (end - start),
+        (offset - tag_hits).max(0),
+    )
+}
+
+/// Find media whose stored embedding is closest to that of an existing item.
+///
+/// The query item itself is excluded. Returns `None` if the media doesn't
+/// exist or has no embedding from the current model.
+pub async fn find_similar_to_media(
+    media_id: Uuid,
+    limit: i64,
+    offset: i64,
+    state: &AppState,
+) -> Result<Option<Vec<SearchHit>>> {
+    InvalidPage::check(limit, offset)?;
+
+    let embedding = sqlx::query_scalar!(
+        r#"
+        SELECT embedding::real[] as "embedding!"
+        FROM embeddings
+        WHERE media_id = $1 AND model_name = $2
+        LIMIT 1
+        "#,
+        media_id,
+        state.embedder.model_id()
+    )
+    .fetch_optional(&state.db_pool)
+    .await?;
+
+    let Some(embedding) = embedding else {
+        return Ok(None);
+    };
+
+    let hits = nearest_neighbours(&embedding, Some(media_id), None, limit, offset, state).await?;
+    Ok(Some(hits))
+}
+
+/// Find media visually similar to an image that may not be in the library.
+///
+/// If the image's content is already stored, that media item is excluded.
+pub async fn find_similar_to_image(
+    media_details: MediaDetails,
+    limit: i64,
+    offset: i64,
+    state: &AppState,
+) -> Result<Vec<SearchHit>> {
+    InvalidPage::check(limit, offset)?;
+
+    let encode_start = Instant::now();
+    // A forward pass takes long enough on the CPU to stall the runtime
+    let embedder = Arc::clone(&state.embedder);
+    let image = media_details.image;
+    let embedding = tokio::task::spawn_blocking(move || -> Result<_> {
+        Ok(embedder
+            .encode_image(&image)?
+            .flatten_all()?
+            .to_vec1::<f32>()?)
+    })
+    .await??;
+    debug!(
+        "Encoded query image {} in {:?}",
+        media_details.file_path,
+        encode_start.elapsed()
+    );
+
+    nearest_neighbours(
+        &embedding,
+        None,
+        Some(&media_details.content_hash),
+        limit,
+        offset,
+        state,
+    )
+    .await
+}
+
+async fn nearest_neighbours(
+    embedding: &[f32],
+    exclude_media_id: Option<Uuid>,
+    exclude_content_hash: Option<&str>,
+    limit: i64,
+    offset: i64,
+    state: &AppState,
+) -> Result<Vec<SearchHit>> {
+    let query_start = Instant::now();
+    let rows = sqlx::query!(
+        r#"
+        SELECT m.id, m.filename, m.file_path, m.content_type,
+               m.captured_at, m.latitude, m.longitude,
+               1 - (e.embedding <=> $1::vector) as "similarity!"
+        FROM media m
+        JOIN embeddings e ON m.id = e.media_id
+        WHERE e.model_name = $6
+          AND ($2::uuid IS NULL OR m.id <> $2)
+          AND ($3::text IS NULL OR m.content_hash IS DISTINCT FROM $3)
+        ORDER BY e.embedding <=> $1::vector
+        LIMIT $4 OFFSET $5
+        "#,
+        embedding as &[f32],
+        exclude_media_id,
+        exclude_content_hash,
+        limit,
+        offset,
+        state.embedder.model_id()
+    )
+    .fetch_all(&state.db_pool)
+    .await?;
+    debug!(
+        "Similarity search returned {} hits in {:?}",
+        rows.len(),
+        query_start.elapsed()
+    );
+
+    Ok(rows
+        .into_iter()
+        .map(|row| SearchHit {
+            media_id: row.id,
+            filename: row.filename,
+            file_path: row.file_path,
+            content_type: row.content_type,
+            similarity: row.similarity,
+            tier: SearchTier::Vector,
+            captured_at: row.captured_at,
+            latitude: row.latitude,
+            longitude: row.longitude,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_words_lowercases_and_strips_punctuation() {
+        assert_eq!(
+            query_words("Felix, on the SOFA!"),
+            vec!["felix", "on", "the", "sofa"]
+        );
+    }
+
+    #[test]
+    fn test_exact_tag_candidates_include_multi_word_runs() {
+        let words = query_words("uncle bob smiling");
+        let candidates = exact_tag_candidates(&words);
+        assert!(candidates.contains(&"uncle bob".to_string()));
+        assert!(candidates.contains(&"smiling".to_string()));
+        assert!(!candidates.contains(&"uncle smiling".to_string()));
+    }
+
+    #[test]
+    fn test_exact_tag_candidates_are_bounded_for_long_queries() {
+        let words = query_words(&"word ".repeat(10_000));
+        assert_eq!(words.len(), MAX_QUERY_WORDS);
+        let candidates = exact_tag_candidates(&words);
+        assert!(candidates.len() <= MAX_QUERY_WORDS * MAX_TAG_WORDS);
+        assert!(candidates
+            .iter()
+            .all(|candidate| candidate.split(' ').count() <= MAX_TAG_WORDS));
+    }
+
+    #[test]
+    fn test_split_page_puts_tag_hits_first() {
+        // Three tag hits fill the start of the first page
+        assert_eq!(split_page(3, 10, 0), (0..3, 7, 0));
+        // Past the tag hits only the vector query is paged
+        assert_eq!(split_page(3, 10, 10), (3..3, 10, 7));
+        // A page of tag hits alone skips the vector query
+        assert_eq!(split_page(30, 10, 10), (10..20, 0, 0));
+        assert_eq!(split_page(0, 5, 5), (0..0, 5, 5));
+    }
+
+    #[test]
+    fn test_search_query_rejects_invalid_pages() {
+        let query = SearchQuery::new("cat");
+        assert!(query.check_page().is_ok());
+        assert!(SearchQuery {
+            limit: 0,
+            ..query.clone()
+        }
+        .check_page()
+        .is_err());
+        assert!(SearchQuery {
+            limit: -1,
+            ..query.clone()
+        }
+        .check_page()
+        .is_err());
+        assert!(SearchQuery {
+            offset: -1,
+            ..query
+        }
+        .check_page()
+        .is_err());
+    }
+
+    #[test]
+    fn test_partial_tag_terms_skip_short_words() {
+        let words = query_words("a cat on a mat");
+        assert_eq!(partial_tag_terms(&words), vec!["cat", "mat"]);
+    }
+}
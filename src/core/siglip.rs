@@ -0,0 +1,153 @@
+use crate::core::config::LongTextMode;
+use crate::core::embedding::{average_windows, Embedder, TextFit, TextWindow};
+use crate::core::preprocessing::ImagePreprocessing;
+use anyhow::{Error as E, Result as AnyhowResult};
+use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::{clip, siglip};
+use image::DynamicImage;
+use std::path::Path;
+use tokenizers::Tokenizer;
+
+/// Name recorded alongside stored embeddings
+pub const MODEL_ID: &str = "siglip-base-patch16-224";
+
+/// Hugging Face hub repository of the weights
+pub const HUB_ID: &str = "google/siglip-base-patch16-224";
+
+/// Google's SigLIP base model (`google/siglip-base-patch16-224`).
+pub struct SiglipEmbedder {
+    model: siglip::Model,
+    tokenizer: Tokenizer,
+    device: Device,
+    config: siglip::Config,
+    preprocessing: ImagePreprocessing,
+    text_window: TextWindow,
+}
+
+impl SiglipEmbedder {
+    pub fn new(
+        model_path: &Path,
+        tokenizer_path: &Path,
+        preprocessing: ImagePreprocessing,
+        long_text: LongTextMode,
+        device: Device,
+    ) -> AnyhowResult<Self> {
+        let config = siglip::Config::base_patch16_224();
+        if preprocessing.crop_size as usize != config.vision_config.image_size {
+            return Err(E::msg(format!(
+                "Preprocessing crops images to {}px but {} expects {}px input",
+                preprocessing.crop_size, MODEL_ID, config.vision_config.image_size
+            )));
+        }
+        let vb = unsafe {
+            VarBuilder::from_mmaped_safetensors(&[model_path.to_path_buf()], DType::F32, &device)?
+        };
+        let model = siglip::Model::new(&config, vb)?;
+        let tokenizer = Tokenizer::from_file(tokenizer_path).map_err(E::msg)?;
+        // SigLIP's tokenizer only appends an end of sequence token
+        let text_window = TextWindow {
+            leading: 0,
+            limit: config.text_config.max_position_embeddings,
+            mode: long_text,
+        };
+
+        Ok(Self {
+            model,
+            tokenizer,
+            device,
+            config,
+            preprocessing,
+            text_window,
+        })
+    }
+
+    /// Dimension of the embeddings SigLIP base produces
+    pub fn model_dimension() -> usize {
+        siglip::Config::base_patch16_224().text_config.hidden_size
+    }
+
+    /// Load an image into a normalized `(3, size, size)` tensor, as described
+    /// by the model's [`ImagePreprocessing`]. SigLIP squashes the image to a
+    /// square without cropping.
+    fn load_image_tensor(&self, image: &DynamicImage) -> AnyhowResult<Tensor> {
+        let size = self.preprocessing.crop_size as usize;
+        let values = self.preprocessing.pixel_values(image);
+        Ok(Tensor::from_vec(values, (3, size, size), &self.device)?)
+    }
+
+    fn tokenize_sequence(&self, sequence: &str) -> AnyhowResult<Vec<u32>> {
+        let encoding = self.tokenizer.encode(sequence, true).map_err(E::msg)?;
+        Ok(encoding.get_ids().to_vec())
+    }
+
+    /// Pad a batch of token sequences into a
+    /// `(batch, max_position_embeddings)` tensor.
+    ///
+    /// Unlike CLIP, SigLIP pools the last position and was trained on inputs
+    /// padded to the full context, so every sequence is padded to exactly
+    /// that length.
+    fn pad_batch(&self, token_ids: Vec<Vec<u32>>) -> AnyhowResult<Tensor> {
+        let text_config = &self.config.text_config;
+
+        let rows = token_ids
+            .into_iter()
+            .map(|mut tokens| {
+                tokens.resize(
+                    text_config.max_position_embeddings,
+                    text_config.pad_token_id,
+                );
+                Tensor::new(tokens, &self.device)
+            })
+            .collect::<candle_core::Result<Vec<_>>>()?;
+
+        Ok(Tensor::stack(&rows, 0)?)
+    }
+}
+
+impl Embedder for SiglipEmbedder {
+    fn model_id(&self) -> &str {
+        MODEL_ID
+    }
+
+    fn dimension(&self) -> usize {
+        self.config.text_config.hidden_size
+    }
+
+    fn encode_images(&self, images: &[DynamicImage]) -> AnyhowResult<Tensor> {
+        if images.is_empty() {
+            return Err(E::msg("Cannot encode an empty batch of images"));
+        }
+
+        let tensors = images
+            .iter()
+            .map(|image| self.load_image_tensor(image))
+            .collect::<AnyhowResult<Vec<_>>>()?;
+        let batch = Tensor::stack(&tensors, 0)?;
+        let embedding = self.model.get_image_features(&batch)?;
+        Ok(clip::div_l2_norm(&embedding)?)
+    }
+
+    fn encode_texts(&self, texts: &[&str]) -> AnyhowResult<(Tensor, Vec<TextFit>)> {
+        if texts.is_empty() {
+            return Err(E::msg("Cannot encode an empty batch of texts"));
+        }
+
+        let mut sequences = Vec::with_capacity(texts.len());
+        let mut windows = Vec::with_capacity(texts.len());
+        let mut fits = Vec::with_capacity(texts.len());
+        for text in texts {
+            let (fit, split) = self.text_window.split(self.tokenize_sequence(text)?)?;
+            fits.push(fit);
+            windows.push(split.len());
+            sequences.extend(split);
+        }
+
+        let input_ids = self.pad_batch(sequences)?;
+        let embedding = self.model.get_text_features(&input_ids)?;
+        Ok((
+            average_windows(&clip::div_l2_norm(&embedding)?, &windows)?,
+            fits,
+        ))
+    }
+}
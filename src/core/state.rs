@@ -1,19 +1,19 @@
-use crate::core::config::Config;
-use crate::core::embedding::ClipEmbedder;
+use crate::core::config::{Config, DeviceSetting};
+use crate::core::embedding::{load_embedder, Embedder};
 use anyhow::Result;
 use candle_core::Device;
 use sqlx::postgres::PgPool;
-use std::path::Path;
 use std::sync::Arc;
+use tracing::info;
 
 /// Application state containing shared resources
+#[derive(Clone)]
 pub struct AppState {
     pub config: Config,
     pub db_pool: PgPool,
-    pub embedder: Arc<ClipEmbedder>,
+    pub embedder: Arc<dyn Embedder>,
 }
 
-// TODO: load models from huggingface ids instead? or make paths optional?
 impl AppState {
     /// Create a new application state with all resources initialized
     pub async fn new(config: Config) -> Result<Self> {
@@ -21,23 +21,64 @@ impl AppState {
         let db_pool = crate::core::db::create_pool(&config).await?;
         crate::core::db::check_connection(&db_pool).await?;
 
+        // Media stored before content hashes were recorded would otherwise
+        // never be recognised as duplicates
+        crate::core::ingest::backfill_content_hashes(&db_pool).await?;
+
         // TODO: can use a job queue to speed up ingestion and load multiple models in parallel depending on VRAM available and number of jobs.
-        // Initialize CLIP model
-        let embedder = ClipEmbedder::new(
-            config.embedding.model_path.as_ref().map(Path::new).unwrap(),
-            config
-                .embedding
-                .tokenizer_path
-                .as_ref()
-                .map(Path::new)
-                .unwrap(),
-            Device::cuda_if_available(0)?,
+        // Initialize the embedding model
+        let embedder = load_embedder(
+            &config.embedding,
+            select_device(config.embedding.device_setting())?,
         )?;
 
+        // The vector column's dimension is fixed by the migrations, so catch a
+        // mismatched model here rather than on the first insert
+        if let Some(column_dimension) =
+            crate::core::db::embedding_column_dimension(&db_pool).await?
+        {
+            if column_dimension != embedder.dimension() {
+                anyhow::bail!(
+                    "Model {} produces {}-dimensional embeddings but the embeddings.embedding \
+                     column is vector({}). Choose a model with a matching dimension or migrate \
+                     the column (and re-ingest) to vector({}).",
+                    embedder.model_id(),
+                    embedder.dimension(),
+                    column_dimension,
+                    embedder.dimension()
+                );
+            }
+        }
+
         Ok(Self {
             config,
             db_pool,
-            embedder: Arc::new(embedder),
+            embedder,
         })
     }
 }
+
+/// Resolve the configured device setting to a candle device.
+fn select_device(setting: DeviceSetting) -> Result<Device> {
+    let device = match setting {
+        DeviceSetting::Cpu => Device::Cpu,
+        DeviceSetting::Auto => Device::cuda_if_available(0)?,
+        DeviceSetting::Cuda(ordinal) => {
+            if !cfg!(feature = "cuda") {
+                anyhow::bail!(
+                    "Device cuda:{} was requested but this build has no CUDA support. \
+                     Rebuild with `--features cuda` or set embedding.device = \"cpu\".",
+                    ordinal
+                );
+            }
+            Device::new_cuda(ordinal)?
+        }
+    };
+
+    match &device {
+        Device::Cuda(_) => info!("Using CUDA device for inference ({:?})", setting),
+        _ => info!("Using CPU for inference ({:?})", setting),
+    }
+
+    Ok(device)
+}
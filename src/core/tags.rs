@@ -0,0 +1,230 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::postgres::PgPool;
+use thiserror::Error;
+use uuid::Uuid;
+
+#[derive(Debug, Error)]
+pub enum TagError {
+    #[error("Media not found: {0}")]
+    MediaNotFound(Uuid),
+
+    #[error("Tag not found: {0}")]
+    TagNotFound(Uuid),
+
+    #[error("Tag already exists: {0}")]
+    AlreadyExists(String),
+
+    #[error("Tag name cannot be empty")]
+    EmptyName,
+
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+}
+
+#[derive(Debug, Serialize)]
+pub struct Tag {
+    pub id: Uuid,
+    pub name: String,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TagUsage {
+    pub id: Uuid,
+    pub name: String,
+    pub media_count: i64,
+}
+
+/// Trim a tag name and reject empty ones.
+pub fn normalize_tag_name(name: &str) -> Result<String, TagError> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(TagError::EmptyName);
+    }
+    Ok(name.to_string())
+}
+
+/// Create a new tag, failing if one with the same name already exists.
+pub async fn create_tag(pool: &PgPool, name: &str) -> Result<Tag, TagError> {
+    let name = normalize_tag_name(name)?;
+
+    sqlx::query_as!(
+        Tag,
+        r#"
+        INSERT INTO tags (id, name)
+        VALUES ($1, $2)
+        ON CONFLICT (name) DO NOTHING
+        RETURNING id, name, created_at
+        "#,
+        Uuid::new_v4(),
+        name
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or(TagError::AlreadyExists(name))
+}
+
+pub async fn delete_tag(pool: &PgPool, tag_id: Uuid) -> Result<(), TagError> {
+    let result = sqlx::query!("DELETE FROM tags WHERE id = $1", tag_id)
+        .execute(pool)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(TagError::TagNotFound(tag_id));
+    }
+
+    Ok(())
+}
+
+/// List every tag along with the number of media items carrying it.
+pub async fn list_tags_with_usage(pool: &PgPool) -> Result<Vec<TagUsage>, TagError> {
+    let tags = sqlx::query_as!(
+        TagUsage,
+        r#"
+        SELECT t.id, t.name, COUNT(mt.media_id) as "media_count!"
+        FROM tags t
+        LEFT JOIN media_tags mt ON t.id = mt.tag_id
+        GROUP BY t.id, t.name
+        ORDER BY t.name
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(tags)
+}
+
+pub async fn get_media_tags(pool: &PgPool, media_id: Uuid) -> Result<Vec<Tag>, TagError> {
+    ensure_media_exists(pool, media_id).await?;
+
+    let tags = sqlx::query_as!(
+        Tag,
+        r#"
+        SELECT t.id, t.name, t.created_at
+        FROM tags t
+        JOIN media_tags mt ON t.id = mt.tag_id
+        WHERE mt.media_id = $1
+        ORDER BY t.name
+        "#,
+        media_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(tags)
+}
+
+/// Add and remove tags on a media item in a single transaction.
+///
+/// Tags in `add` are created by name if they don't exist yet. Removing a tag
+/// the media doesn't carry is a no-op. Returns the media's tags afterwards.
+pub async fn update_media_tags(
+    pool: &PgPool,
+    media_id: Uuid,
+    add: &[String],
+    remove: &[String],
+) -> Result<Vec<Tag>, TagError> {
+    let add = add
+        .iter()
+        .map(|name| normalize_tag_name(name))
+        .collect::<Result<Vec<_>, _>>()?;
+    let remove = remove
+        .iter()
+        .map(|name| normalize_tag_name(name))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut tx = pool.begin().await?;
+
+    let media_exists = sqlx::query_scalar!(
+        r#"SELECT EXISTS(SELECT 1 FROM media WHERE id = $1) as "exists!""#,
+        media_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if !media_exists {
+        return Err(TagError::MediaNotFound(media_id));
+    }
+
+    for name in &add {
+        // The no-op update lets RETURNING yield the id of an existing tag
+        let tag_id = sqlx::query_scalar!(
+            r#"
+            INSERT INTO tags (id, name)
+            VALUES ($1, $2)
+            ON CONFLICT (name) DO UPDATE SET name = EXCLUDED.name
+            RETURNING id
+            "#,
+            Uuid::new_v4(),
+            name
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO media_tags (media_id, tag_id)
+            VALUES ($1, $2)
+            ON CONFLICT DO NOTHING
+            "#,
+            media_id,
+            tag_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    if !remove.is_empty() {
+        sqlx::query!(
+            r#"
+            DELETE FROM media_tags mt
+            USING tags t
+            WHERE mt.tag_id = t.id
+              AND mt.media_id = $1
+              AND t.name = ANY($2)
+            "#,
+            media_id,
+            &remove
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    get_media_tags(pool, media_id).await
+}
+
+pub async fn remove_media_tag(pool: &PgPool, media_id: Uuid, tag_id: Uuid) -> Result<(), TagError> {
+    ensure_media_exists(pool, media_id).await?;
+
+    let result = sqlx::query!(
+        "DELETE FROM media_tags WHERE media_id = $1 AND tag_id = $2",
+        media_id,
+        tag_id
+    )
+    .execute(pool)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(TagError::TagNotFound(tag_id));
+    }
+
+    Ok(())
+}
+
+async fn ensure_media_exists(pool: &PgPool, media_id: Uuid) -> Result<(), TagError> {
+    let exists = sqlx::query_scalar!(
+        r#"SELECT EXISTS(SELECT 1 FROM media WHERE id = $1) as "exists!""#,
+        media_id
+    )
+    .fetch_one(pool)
+    .await?;
+
+    if !exists {
+        return Err(TagError::MediaNotFound(media_id));
+    }
+
+    Ok(())
+}
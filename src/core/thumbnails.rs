@@ -0,0 +1,256 @@
+use crate::core::config::StorageConfig;
+use anyhow::{Context, Result};
+use image::codecs::jpeg::JpegEncoder;
+use image::DynamicImage;
+use sqlx::postgres::PgPool;
+use sqlx::PgConnection;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+const THUMBNAIL_MIME_TYPE: &str = "image/jpeg";
+const JPEG_QUALITY: u8 = 85;
+
+/// A thumbnail written to disk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Thumbnail {
+    /// Bound on the longest edge this thumbnail was generated for
+    pub size: u32,
+    pub file_path: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Thumbnail {
+    pub fn content_type(&self) -> &'static str {
+        THUMBNAIL_MIME_TYPE
+    }
+}
+
+/// Content-addressed location of a thumbnail:
+/// `<media_path>/thumbnails/<size>/<hash[..2]>/<hash>.jpg`
+pub fn thumbnail_path(media_path: &Path, content_hash: &str, size: u32) -> PathBuf {
+    let prefix = content_hash.get(..2).unwrap_or(content_hash);
+    media_path
+        .join("thumbnails")
+        .join(size.to_string())
+        .join(prefix)
+        .join(format!("{}.jpg", content_hash))
+}
+
+/// Write a thumbnail for every configured size.
+///
+/// Because thumbnails are addressed by content hash, a size that already
+/// exists on disk is reused rather than re-encoded.
+pub fn generate_thumbnails(
+    image: &DynamicImage,
+    content_hash: &str,
+    storage: &StorageConfig,
+) -> Result<Vec<Thumbnail>> {
+    storage
+        .thumbnail_sizes
+        .iter()
+        .map(|&size| {
+            let path = thumbnail_path(&storage.media_path, content_hash, size);
+            let (width, height) = if path.exists() {
+                image::image_dimensions(&path)
+                    .with_context(|| format!("Failed to read thumbnail {:?}", path))?
+            } else {
+                write_thumbnail(image, size, &path)?
+            };
+
+            Ok(Thumbnail {
+                size,
+                file_path: path.to_string_lossy().to_string(),
+                width,
+                height,
+            })
+        })
+        .collect()
+}
+
+fn write_thumbnail(image: &DynamicImage, size: u32, path: &Path) -> Result<(u32, u32)> {
+    // Never upscale images that are already smaller than the bound
+    let thumbnail = if image.width() <= size && image.height() <= size {
+        image.to_rgb8()
+    } else {
+        image.thumbnail(size, size).to_rgb8()
+    };
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    // Write to a temporary file first so a crash never leaves a truncated
+    // thumbnail at the content-addressed path
+    let tmp_path = path.with_extension("jpg.tmp");
+    let writer = BufWriter::new(File::create(&tmp_path)?);
+    thumbnail
+        .write_with_encoder(JpegEncoder::new_with_quality(writer, JPEG_QUALITY))
+        .with_context(|| format!("Failed to encode thumbnail {:?}", path))?;
+    std::fs::rename(&tmp_path, path)?;
+
+    Ok(thumbnail.dimensions())
+}
+
+/// Record thumbnails for a media item, replacing any existing rows of the
+/// same size.
+pub async fn save_thumbnails(
+    conn: &mut PgConnection,
+    media_id: Uuid,
+    thumbnails: &[Thumbnail],
+) -> Result<()> {
+    for thumbnail in thumbnails {
+        sqlx::query!(
+            r#"
+            INSERT INTO thumbnails (media_id, size, file_path, width, height)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (media_id, size) DO UPDATE
+            SET file_path = EXCLUDED.file_path,
+                width = EXCLUDED.width,
+                height = EXCLUDED.height
+            "#,
+            media_id,
+            thumbnail.size as i32,
+            thumbnail.file_path,
+            thumbnail.width as i32,
+            thumbnail.height as i32
+        )
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// All recorded thumbnails of a media item, smallest first.
+pub async fn list_thumbnails(pool: &PgPool, media_id: Uuid) -> Result<Vec<Thumbnail>> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT size, file_path, width, height
+        FROM thumbnails
+        WHERE media_id = $1
+        ORDER BY size
+        "#,
+        media_id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| Thumbnail {
+            size: row.size as u32,
+            file_path: row.file_path,
+            width: row.width as u32,
+            height: row.height as u32,
+        })
+        .collect())
+}
+
+/// Pick the smallest thumbnail that is at least `size` pixels, falling back
+/// to the largest one available. Without a size the smallest is returned.
+pub fn select_thumbnail(thumbnails: &[Thumbnail], size: Option<u32>) -> Option<&Thumbnail> {
+    let size = size.unwrap_or(0);
+    thumbnails
+        .iter()
+        .filter(|thumbnail| thumbnail.size >= size)
+        .min_by_key(|thumbnail| thumbnail.size)
+        .or_else(|| thumbnails.iter().max_by_key(|thumbnail| thumbnail.size))
+}
+
+/// A media item whose thumbnails are incomplete.
+#[derive(Debug, Clone)]
+pub struct MissingThumbnails {
+    pub media_id: Uuid,
+    pub file_path: String,
+}
+
+/// Find media that lack a row or a file on disk for any of `sizes`.
+///
+/// With `force` every media item is returned.
+pub async fn find_missing_thumbnails(
+    pool: &PgPool,
+    sizes: &[u32],
+    force: bool,
+) -> Result<Vec<MissingThumbnails>> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT m.id, m.file_path, t.size as "size?", t.file_path as "thumbnail_path?"
+        FROM media m
+        LEFT JOIN thumbnails t ON t.media_id = m.id
+        ORDER BY m.created_at, m.id
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut order = Vec::new();
+    let mut recorded: HashMap<Uuid, (String, Vec<(u32, String)>)> = HashMap::new();
+    for row in rows {
+        let entry = recorded.entry(row.id).or_insert_with(|| {
+            order.push(row.id);
+            (row.file_path, Vec::new())
+        });
+        if let (Some(size), Some(path)) = (row.size, row.thumbnail_path) {
+            entry.1.push((size as u32, path));
+        }
+    }
+
+    Ok(order
+        .into_iter()
+        .filter_map(|media_id| {
+            let (file_path, thumbnails) = recorded.remove(&media_id)?;
+            let complete = sizes.iter().all(|size| {
+                thumbnails
+                    .iter()
+                    .any(|(s, path)| s == size && Path::new(path).exists())
+            });
+            (force || !complete).then_some(MissingThumbnails {
+                media_id,
+                file_path,
+            })
+        })
+        .collect())
+}
+
+/// Remove any thumbnail files of the given content so they are re-encoded.
+pub fn remove_thumbnail_files(media_path: &Path, content_hash: &str, sizes: &[u32]) {
+    for &size in sizes {
+        let _ = std::fs::remove_file(thumbnail_path(media_path, content_hash, size));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn thumbnail(size: u32) -> Thumbnail {
+        Thumbnail {
+            size,
+            file_path: format!("{}.jpg", size),
+            width: size,
+            height: size,
+        }
+    }
+
+    #[test]
+    fn test_thumbnail_path_is_content_addressed() {
+        let path = thumbnail_path(Path::new("media"), "abcdef", 256);
+        assert_eq!(path, Path::new("media/thumbnails/256/ab/abcdef.jpg"));
+    }
+
+    #[test]
+    fn test_select_thumbnail() {
+        let thumbnails = [thumbnail(256), thumbnail(1024)];
+        assert_eq!(select_thumbnail(&thumbnails, None).unwrap().size, 256);
+        assert_eq!(select_thumbnail(&thumbnails, Some(300)).unwrap().size, 1024);
+        assert_eq!(
+            select_thumbnail(&thumbnails, Some(4096)).unwrap().size,
+            1024
+        );
+        assert!(select_thumbnail(&[], Some(256)).is_none());
+    }
+}
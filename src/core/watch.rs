@@ -0,0 +1,265 @@
+use crate::core::ingest::{mark_missing, process_image, update_media_paths, IngestOutcome};
+use crate::core::media::{extract_media_details_from_path, images_under, is_supported_image};
+use crate::core::state::AppState;
+use anyhow::{anyhow, Context, Result};
+use notify_debouncer_full::notify::event::{ModifyKind, RenameMode};
+use notify_debouncer_full::notify::{Event, EventKind, RecursiveMode};
+use notify_debouncer_full::{new_debouncer, DebounceEventResult};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+/// What a filesystem event means for the library.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum WatchAction {
+    /// A file or directory appeared or its contents changed
+    Ingest(PathBuf),
+    /// A file or directory was renamed within the watched tree
+    Rename { from: PathBuf, to: PathBuf },
+    /// A file or directory disappeared
+    Remove(PathBuf),
+}
+
+/// Watch a directory and keep the library in sync with it until the watcher
+/// fails.
+///
+/// New and modified images are fed through [`process_image`] once they have
+/// been quiet for `ingest.watch_debounce_ms`, renames update the stored
+/// paths, and deletions mark media as missing.
+pub async fn watch(dir: &Path, state: &AppState) -> Result<()> {
+    let dir = dir
+        .canonicalize()
+        .with_context(|| format!("Cannot watch {:?}", dir))?;
+    // Thumbnails are images too, but must never be ingested
+    let thumbnails_dir = state
+        .config
+        .storage
+        .media_path
+        .canonicalize()
+        .ok()
+        .map(|path| path.join("thumbnails"));
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<DebounceEventResult>();
+    let debounce = Duration::from_millis(state.config.ingest.watch_debounce_ms);
+    let mut debouncer = new_debouncer(debounce, None, move |result| {
+        let _ = tx.send(result);
+    })?;
+    debouncer.watch(&dir, RecursiveMode::Recursive)?;
+    info!("Watching {:?} for changes", dir);
+
+    while let Some(result) = rx.recv().await {
+        let events = match result {
+            Ok(events) => events,
+            Err(errors) => {
+                for error in errors {
+                    warn!("Watch error: {}", error);
+                }
+                continue;
+            }
+        };
+
+        for event in events {
+            for action in classify_event(&event) {
+                let ignored = thumbnails_dir.as_ref().is_some_and(|thumbnails| {
+                    action_paths(&action)
+                        .iter()
+                        .all(|path| path.starts_with(thumbnails))
+                });
+                if ignored {
+                    continue;
+                }
+
+                if let Err(e) = apply_action(action, state, thumbnails_dir.as_deref()).await {
+                    warn!("{:#}", e);
+                }
+            }
+        }
+    }
+
+    Err(anyhow!("Watcher for {:?} stopped unexpectedly", dir))
+}
+
+fn classify_event(event: &Event) -> Vec<WatchAction> {
+    let each = |action: fn(PathBuf) -> WatchAction| -> Vec<WatchAction> {
+        event.paths.iter().cloned().map(action).collect()
+    };
+
+    match event.kind {
+        EventKind::Create(_) => each(WatchAction::Ingest),
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+            vec![WatchAction::Rename {
+                from: event.paths[0].clone(),
+                to: event.paths[1].clone(),
+            }]
+        }
+        EventKind::Modify(ModifyKind::Name(RenameMode::From)) => each(WatchAction::Remove),
+        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => each(WatchAction::Ingest),
+        // Unpaired renames only tell us the path changed, so look at whether
+        // it still exists
+        EventKind::Modify(ModifyKind::Name(_)) => event
+            .paths
+            .iter()
+            .map(|path| {
+                if path.exists() {
+                    WatchAction::Ingest(path.clone())
+                } else {
+                    WatchAction::Remove(path.clone())
+                }
+            })
+            .collect(),
+        // Permission and timestamp changes don't affect the content
+        EventKind::Modify(ModifyKind::Metadata(_)) => Vec::new(),
+        EventKind::Modify(_) => each(WatchAction::Ingest),
+        EventKind::Remove(_) => each(WatchAction::Remove),
+        EventKind::Access(_) | EventKind::Any | EventKind::Other => Vec::new(),
+    }
+}
+
+fn action_paths(action: &WatchAction) -> Vec<&Path> {
+    match action {
+        WatchAction::Ingest(path) | WatchAction::Remove(path) => vec![path.as_path()],
+        WatchAction::Rename { from, to } => vec![from.as_path(), to.as_path()],
+    }
+}
+
+async fn apply_action(
+    action: WatchAction,
+    state: &AppState,
+    thumbnails_dir: Option<&Path>,
+) -> Result<()> {
+    match action {
+        WatchAction::Ingest(path) => ingest_path(&path, state, thumbnails_dir).await,
+        WatchAction::Rename { from, to } => {
+            let updated = update_media_paths(
+                &state.db_pool,
+                &from.to_string_lossy(),
+                &to.to_string_lossy(),
+            )
+            .await?;
+
+            if updated > 0 {
+                info!("Renamed {:?} to {:?} ({} media)", from, to, updated);
+                Ok(())
+            } else {
+                // Nothing known lived there, so treat it as new content
+                ingest_path(&to, state, thumbnails_dir).await
+            }
+        }
+        WatchAction::Remove(path) => {
+            let missing = mark_missing(&state.db_pool, &path.to_string_lossy(), None).await?;
+            if missing > 0 {
+                info!("Marked {} media missing under {:?}", missing, path);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Ingest a file, or every supported image below a directory.
+async fn ingest_path(path: &Path, state: &AppState, thumbnails_dir: Option<&Path>) -> Result<()> {
+    if path.is_dir() {
+        let files = {
+            let path = path.to_path_buf();
+            let exclude: Vec<PathBuf> = thumbnails_dir.map(Path::to_path_buf).into_iter().collect();
+            tokio::task::spawn_blocking(move || images_under(&path, &exclude)).await?
+        };
+        for file in files {
+            if let Err(e) = ingest_file(&file, state).await {
+                warn!("{:#}", e);
+            }
+        }
+        return Ok(());
+    }
+
+    if !path.is_file() || !is_supported_image(path) {
+        debug!("Ignoring {:?}", path);
+        return Ok(());
+    }
+
+    ingest_file(path, state).await
+}
+
+async fn ingest_file(path: &Path, state: &AppState) -> Result<()> {
+    let details = {
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            extract_media_details_from_path(&path)
+                .map_err(|e| anyhow!("Error extracting details from {:?}: {}", path, e))
+        })
+        .await??
+    };
+
+    let outcome = process_image(details, state.config.ingest.import_mode, state)
+        .await
+        .with_context(|| format!("Error ingesting {:?}", path))?;
+
+    match outcome {
+        IngestOutcome::New(id) => {
+            // A file rewritten in place no longer holds the content it used to
+            mark_missing(&state.db_pool, &path.to_string_lossy(), Some(id)).await?;
+            info!("Ingested {:?} as {}", path, id);
+        }
+        IngestOutcome::Moved(id) => info!("Updated path of {} to {:?}", id, path),
+        IngestOutcome::Skipped(_) => debug!("{:?} is already in the library", path),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify_debouncer_full::notify::event::{CreateKind, DataChange, MetadataKind, RemoveKind};
+
+    fn event(kind: EventKind, paths: &[&str]) -> Event {
+        paths
+            .iter()
+            .fold(Event::new(kind), |event, path| event.add_path(path.into()))
+    }
+
+    #[test]
+    fn test_classify_create_and_modify() {
+        assert_eq!(
+            classify_event(&event(EventKind::Create(CreateKind::File), &["/a.jpg"])),
+            vec![WatchAction::Ingest("/a.jpg".into())]
+        );
+        assert_eq!(
+            classify_event(&event(
+                EventKind::Modify(ModifyKind::Data(DataChange::Content)),
+                &["/a.jpg"]
+            )),
+            vec![WatchAction::Ingest("/a.jpg".into())]
+        );
+        assert!(classify_event(&event(
+            EventKind::Modify(ModifyKind::Metadata(MetadataKind::Permissions)),
+            &["/a.jpg"]
+        ))
+        .is_empty());
+    }
+
+    #[test]
+    fn test_classify_rename_and_remove() {
+        assert_eq!(
+            classify_event(&event(
+                EventKind::Modify(ModifyKind::Name(RenameMode::Both)),
+                &["/a.jpg", "/b.jpg"]
+            )),
+            vec![WatchAction::Rename {
+                from: "/a.jpg".into(),
+                to: "/b.jpg".into()
+            }]
+        );
+        assert_eq!(
+            classify_event(&event(
+                EventKind::Modify(ModifyKind::Name(RenameMode::From)),
+                &["/a.jpg"]
+            )),
+            vec![WatchAction::Remove("/a.jpg".into())]
+        );
+        assert_eq!(
+            classify_event(&event(EventKind::Remove(RemoveKind::File), &["/a.jpg"])),
+            vec![WatchAction::Remove("/a.jpg".into())]
+        );
+    }
+}
@@ -5,7 +5,8 @@ mod utils;
 
 use clap::{Parser, Subcommand};
 use std::error::Error;
-use tracing::info;
+use tracing::{error, info};
+use uuid::Uuid;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -23,12 +24,19 @@ enum Commands {
 
         #[arg(short, long, default_value_t = 8080)]
         port: u16,
+
+        #[arg(
+            long,
+            help = "Directory to watch for new media while serving (repeatable)"
+        )]
+        watch: Vec<std::path::PathBuf>,
     },
 
     /// Ingest media files
     Ingest {
         /// Path to file or directory
-        path: std::path::PathBuf,
+        #[arg(required_unless_present = "retry_failed")]
+        path: Option<std::path::PathBuf>,
 
         #[arg(short, long)]
         recursive: bool,
@@ -39,6 +47,76 @@ enum Commands {
             help = "Maximum recursion depth when scanning directories"
         )]
         max_depth: Option<usize>,
+
+        #[arg(long, help = "Number of parallel image decode workers")]
+        workers: Option<usize>,
+
+        #[arg(long, help = "Number of images embedded per batch")]
+        batch_size: Option<usize>,
+
+        #[arg(
+            long,
+            help = "Copy or move originals into the library, or reference them in place [copy, move, reference]"
+        )]
+        import_mode: Option<core::config::ImportMode>,
+
+        #[arg(short, long, help = "Skip the confirmation prompt")]
+        yes: bool,
+
+        #[arg(long, help = "List the files that would be ingested and exit")]
+        dry_run: bool,
+
+        #[arg(
+            long,
+            help = "Only ingest files matching this glob, relative to the path (repeatable)"
+        )]
+        include: Vec<String>,
+
+        #[arg(
+            long,
+            help = "Skip files and directories matching this glob, e.g. '**/.thumbnails/**' (repeatable)"
+        )]
+        exclude: Vec<String>,
+
+        #[arg(
+            long,
+            help = "Descend into symlinked directories when scanning (symlinked files are always ingested)"
+        )]
+        follow_symlinks: bool,
+
+        #[arg(
+            long,
+            help = "Retry files that failed in earlier runs, limited to those under the path if given"
+        )]
+        retry_failed: bool,
+
+        #[arg(
+            long,
+            requires = "retry_failed",
+            help = "With --retry-failed, forget failures for files that no longer exist"
+        )]
+        prune: bool,
+    },
+
+    /// Watch a directory and ingest new, changed and renamed images as they appear
+    Watch {
+        /// Directory to watch
+        path: std::path::PathBuf,
+    },
+
+    /// Compare the library roots on disk with the database and reconcile them
+    Rescan {
+        #[arg(
+            long,
+            help = "Delete media whose files are missing instead of flagging them"
+        )]
+        prune: bool,
+
+        #[arg(long, help = "Re-embed media whose file content changed")]
+        reembed: bool,
+
+        #[arg(long, help = "Report differences without changing anything")]
+        dry_run: bool,
     },
 
     /// Search for media
@@ -46,8 +124,56 @@ enum Commands {
         /// Search query
         query: String,
 
-        #[arg(short, long, default_value_t = 10)]
-        limit: usize,
+        #[arg(
+            short,
+            long,
+            default_value_t = 10,
+            value_parser = clap::value_parser!(i64).range(1..)
+        )]
+        limit: i64,
+
+        #[arg(
+            long,
+            default_value_t = 0,
+            value_parser = clap::value_parser!(i64).range(0..),
+            help = "Number of results to skip"
+        )]
+        offset: i64,
+
+        #[arg(long, help = "Minimum cosine similarity (0.0 - 1.0) for a result")]
+        min_similarity: Option<f64>,
+
+        #[arg(
+            long,
+            value_delimiter = ',',
+            help = "Only return media carrying all of these tags"
+        )]
+        tags: Vec<String>,
+
+        #[arg(long, help = "Only return media of this MIME type, e.g. image/png")]
+        content_type: Option<String>,
+    },
+
+    /// Find media visually similar to an existing item or an image file
+    Similar {
+        /// Media ID or path to an image file
+        target: String,
+
+        #[arg(
+            short,
+            long,
+            default_value_t = 10,
+            value_parser = clap::value_parser!(i64).range(1..)
+        )]
+        limit: i64,
+
+        #[arg(
+            long,
+            default_value_t = 0,
+            value_parser = clap::value_parser!(i64).range(0..),
+            help = "Number of results to skip"
+        )]
+        offset: i64,
     },
 
     /// Manage tags
@@ -64,6 +190,96 @@ enum Commands {
 
     /// List all tags
     ListTags,
+
+    /// Manage thumbnails
+    Thumbnails {
+        #[command(subcommand)]
+        command: ThumbnailCommands,
+    },
+
+    /// Manage ingest jobs
+    Jobs {
+        #[command(subcommand)]
+        command: JobCommands,
+    },
+
+    /// Manage embedding model files
+    Models {
+        #[command(subcommand)]
+        command: ModelCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum ThumbnailCommands {
+    /// Rebuild thumbnails that are missing from the database or disk
+    Regenerate {
+        #[arg(long, help = "Rebuild thumbnails for every media item")]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum JobCommands {
+    /// List ingest jobs and their progress
+    List,
+
+    /// Continue an interrupted job with the files it has not processed yet
+    Resume {
+        /// Job ID
+        id: Uuid,
+    },
+
+    /// Cancel a job so it stops starting new files
+    Cancel {
+        /// Job ID
+        id: Uuid,
+    },
+}
+
+#[derive(Subcommand)]
+enum ModelCommands {
+    /// Download the configured model into the local Hugging Face cache
+    Fetch,
+}
+
+/// The path and options of an `ingest` command.
+fn ingest_options(
+    command: Commands,
+) -> Option<(Option<std::path::PathBuf>, cli::commands::IngestOptions)> {
+    let Commands::Ingest {
+        path,
+        recursive,
+        max_depth,
+        workers,
+        batch_size,
+        import_mode,
+        yes,
+        dry_run: _,
+        include,
+        exclude,
+        follow_symlinks,
+        retry_failed,
+        prune,
+    } = command
+    else {
+        return None;
+    };
+
+    let options = cli::commands::IngestOptions {
+        recursive,
+        max_depth,
+        workers,
+        batch_size,
+        import_mode,
+        yes,
+        include,
+        exclude,
+        follow_symlinks,
+        retry_failed,
+        prune,
+    };
+    Some((path, options))
 }
 
 #[tokio::main]
@@ -71,36 +287,96 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // Initialize logging
     utils::logging::init();
 
+    // Parse command line arguments
+    let cli = Cli::parse();
+
     // Load configuration
     let config = core::config::load_config()?;
 
+    // Fetching models must work before the model can be loaded and without a
+    // database
+    if let Commands::Models {
+        command: ModelCommands::Fetch,
+    } = cli.command
+    {
+        info!("Fetching embedding model");
+        cli::commands::fetch_models(&config).await?;
+        return Ok(());
+    }
+
+    // A dry run only lists files, so it must work without the model weights
+    // and leave the database untouched
+    if let Commands::Ingest { dry_run: true, .. } = cli.command {
+        let (path, options) = ingest_options(cli.command).expect("matched an ingest command");
+        cli::commands::preview_ingest(path, options, &config).await?;
+        return Ok(());
+    }
+
     // Initialize application state
     let app_state = core::state::AppState::new(config).await?;
 
-    // Parse command line arguments
-    let cli = Cli::parse();
-
     match cli.command {
-        Commands::Serve { host, port } => {
+        Commands::Serve { host, port, watch } => {
+            for dir in watch {
+                let state = app_state.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = core::watch::watch(&dir, &state).await {
+                        error!("Stopped watching {:?}: {:#}", dir, e);
+                    }
+                });
+            }
+
             info!("Starting API server at {}:{}", host, port);
             api::run_server(host, port, app_state).await?;
         }
-        Commands::Ingest {
-            path,
-            recursive,
-            max_depth,
-        } => {
+        command @ Commands::Ingest { .. } => {
+            let (path, options) = ingest_options(command).expect("matched an ingest command");
             info!(
                 "Ingesting media from {:?} (recursive: {}, max_depth: {})",
                 path,
-                recursive,
-                max_depth.unwrap_or(5)
+                options.recursive,
+                options.max_depth.unwrap_or(5)
             );
-            cli::commands::ingest(path, recursive, &app_state, max_depth).await?;
+            cli::commands::ingest(path, options, &app_state).await?;
+        }
+        Commands::Watch { path } => {
+            info!("Watching {:?}", path);
+            cli::commands::watch(path, &app_state).await?;
         }
-        Commands::Search { query, limit } => {
+        Commands::Rescan {
+            prune,
+            reembed,
+            dry_run,
+        } => {
+            info!("Rescanning library (dry run: {})", dry_run);
+            let options = core::rescan::RescanOptions { prune, reembed };
+            cli::commands::rescan(options, dry_run, &app_state).await?;
+        }
+        Commands::Search {
+            query,
+            limit,
+            offset,
+            min_similarity,
+            tags,
+            content_type,
+        } => {
             info!("Searching for: {}", query);
-            cli::commands::search(query, limit, &app_state).await?;
+            let query = core::search::SearchQuery {
+                limit,
+                offset,
+                min_similarity,
+                filters: core::search::SearchFilters { tags, content_type },
+                ..core::search::SearchQuery::new(query)
+            };
+            cli::commands::search(query, &app_state).await?;
+        }
+        Commands::Similar {
+            target,
+            limit,
+            offset,
+        } => {
+            info!("Finding media similar to: {}", target);
+            cli::commands::similar(target, limit, offset, &app_state).await?;
         }
         Commands::Tag {
             media_id,
@@ -114,6 +390,26 @@ async fn main() -> Result<(), Box<dyn Error>> {
             info!("Listing all tags");
             cli::commands::list_tags(&app_state).await?;
         }
+        Commands::Thumbnails {
+            command: ThumbnailCommands::Regenerate { force },
+        } => {
+            info!("Regenerating thumbnails (force: {})", force);
+            cli::commands::regenerate_thumbnails(force, &app_state).await?;
+        }
+        Commands::Jobs { command } => match command {
+            JobCommands::List => {
+                cli::commands::list_jobs(&app_state).await?;
+            }
+            JobCommands::Resume { id } => {
+                info!("Resuming job {}", id);
+                cli::commands::resume_job(id, &app_state).await?;
+            }
+            JobCommands::Cancel { id } => {
+                info!("Cancelling job {}", id);
+                cli::commands::cancel_job(id, &app_state).await?;
+            }
+        },
+        Commands::Models { .. } => unreachable!("handled before loading the model"),
     }
 
     Ok(())